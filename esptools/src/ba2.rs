@@ -0,0 +1,517 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Fallout 4, Fallout 76 and Starfield archives
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use cap_std::fs::Dir;
+use flate2::read::ZlibDecoder;
+
+use crate::bsa::{normalize_path, relative_path, MAX_COMPRESSION_RATIO};
+use crate::common::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ba2Type {
+	/// general files
+	General,
+	/// textures, split into chunks of mip levels
+	Dx10,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ba2Compression {
+	Zlib,
+	/// raw lz4 blocks, only used by starfield
+	Lz4,
+}
+
+#[derive(Debug)]
+pub struct Ba2Header {
+	pub tag: [u8; 4],
+	/// 1 for fallout 4 and 76, 7 and 8 for the fallout 4 next gen update,
+	/// 2 and 3 for starfield
+	pub version: u32,
+	pub typ: Ba2Type,
+	pub file_count: u32,
+	pub name_table_offset: u64,
+	pub compression: Ba2Compression,
+}
+
+impl Ba2Header {
+	pub fn parse(input: &mut impl Read) -> Result<Self> {
+		let tag = input.eat_tag(b"BTDX")?;
+		let version = input.read_u32::<LittleEndian>()?;
+		let typ = match &input.parse::<[u8; 4]>()? {
+			b"GNRL" => Ba2Type::General,
+			b"DX10" => Ba2Type::Dx10,
+			_ => return Err(Error::InvalidTag),
+		};
+		let file_count = input.read_u32::<LittleEndian>()?;
+		let name_table_offset = input.read_u64::<LittleEndian>()?;
+		let mut compression = Ba2Compression::Zlib;
+		match version {
+			1 | 7 | 8 => (),
+			2 => input.skip(8)?,
+			3 => {
+				input.skip(8)?;
+				compression = match input.read_u32::<LittleEndian>()? {
+					0 => Ba2Compression::Zlib,
+					3 => Ba2Compression::Lz4,
+					_ => return Err(Error::ParseError),
+				};
+			}
+			_ => return Err(Error::ParseError),
+		}
+		Ok(Self { tag, version, typ, file_count, name_table_offset, compression })
+	}
+}
+
+/// A contiguous piece of a file's data, general files always have exactly one
+#[derive(Debug, Clone, Copy)]
+pub struct Ba2Chunk {
+	pub offset: u64,
+	/// zero if the chunk is stored uncompressed
+	pub packed_size: u32,
+	pub unpacked_size: u32,
+	pub start_mip: u16,
+	pub end_mip: u16,
+}
+
+impl Ba2Chunk {
+	fn parse_general(input: &mut impl Read) -> Result<Self> {
+		let result = Self {
+			offset: input.read_u64::<LittleEndian>()?,
+			packed_size: input.read_u32::<LittleEndian>()?,
+			unpacked_size: input.read_u32::<LittleEndian>()?,
+			start_mip: 0,
+			end_mip: 0,
+		};
+		input.skip(4)?; // alignment marker, 0xBAADF00D
+		Ok(result)
+	}
+
+	fn parse_texture(input: &mut impl Read) -> Result<Self> {
+		let result = Self {
+			offset: input.read_u64::<LittleEndian>()?,
+			packed_size: input.read_u32::<LittleEndian>()?,
+			unpacked_size: input.read_u32::<LittleEndian>()?,
+			start_mip: input.read_u16::<LittleEndian>()?,
+			end_mip: input.read_u16::<LittleEndian>()?,
+		};
+		input.skip(4)?; // alignment marker, 0xBAADF00D
+		Ok(result)
+	}
+}
+
+/// Texture metadata, the DDS header is stripped when textures are packed and
+/// has to be rebuilt from this
+#[derive(Debug, Clone, Copy)]
+pub struct Ba2Texture {
+	pub height: u16,
+	pub width: u16,
+	pub mip_count: u8,
+	/// a DXGI_FORMAT
+	pub format: u8,
+	pub flags: u8,
+	pub tile_mode: u8,
+}
+
+impl Ba2Texture {
+	pub fn is_cubemap(&self) -> bool {
+		self.flags & 1 != 0
+	}
+}
+
+#[derive(Debug)]
+pub struct Ba2File {
+	pub name_hash: u32,
+	pub ext: [u8; 4],
+	pub dir_hash: u32,
+	/// full path from the name table, if the archive has one
+	pub path: Option<String>,
+	pub chunks: Vec<Ba2Chunk>,
+	pub texture: Option<Ba2Texture>,
+}
+
+impl Ba2File {
+	fn parse_general(input: &mut impl Read) -> Result<Self> {
+		let name_hash = input.read_u32::<LittleEndian>()?;
+		let ext = input.parse()?;
+		let dir_hash = input.read_u32::<LittleEndian>()?;
+		input.skip(4)?; // flags, always 0x00100100
+		Ok(Self {
+			name_hash,
+			ext,
+			dir_hash,
+			path: None,
+			chunks: vec![Ba2Chunk::parse_general(input)?],
+			texture: None,
+		})
+	}
+
+	fn parse_texture(input: &mut impl Read) -> Result<Self> {
+		let name_hash = input.read_u32::<LittleEndian>()?;
+		let ext = input.parse()?;
+		let dir_hash = input.read_u32::<LittleEndian>()?;
+		input.skip(1)?;
+		let chunk_count = input.read_u8()?;
+		let chunk_header_size = input.read_u16::<LittleEndian>()?;
+		if chunk_header_size != 24 {
+			return Err(Error::BogusSize);
+		}
+		let texture = Ba2Texture {
+			height: input.read_u16::<LittleEndian>()?,
+			width: input.read_u16::<LittleEndian>()?,
+			mip_count: input.read_u8()?,
+			format: input.read_u8()?,
+			flags: input.read_u8()?,
+			tile_mode: input.read_u8()?,
+		};
+		let chunks = (0..chunk_count)
+			.map(|_| Ba2Chunk::parse_texture(input))
+			.collect::<Result<_>>()?;
+		Ok(Self {
+			name_hash,
+			ext,
+			dir_hash,
+			path: None,
+			chunks,
+			texture: Some(texture),
+		})
+	}
+
+	/// size of the file once extracted, not including any rebuilt DDS header
+	pub fn unpacked_size(&self) -> u64 {
+		self.chunks.iter().map(|c| c.unpacked_size as u64).sum()
+	}
+}
+
+pub struct Ba2Archive<R: Read + Seek> {
+	input: R,
+	header: Ba2Header,
+	files: Vec<Ba2File>,
+	paths: HashMap<Box<[u8]>, usize>,
+}
+
+impl Ba2Archive<BufReader<File>> {
+	pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+		Self::new(BufReader::new(File::open(path)?))
+	}
+}
+
+impl<R: Read + Seek> Ba2Archive<R> {
+	pub fn new(mut input: R) -> Result<Self> {
+		input.seek(SeekFrom::Start(0))?;
+		let header = Ba2Header::parse(&mut input)?;
+		let mut files = (0..header.file_count)
+			.map(|_| match header.typ {
+				Ba2Type::General => Ba2File::parse_general(&mut input),
+				Ba2Type::Dx10 => Ba2File::parse_texture(&mut input),
+			})
+			.collect::<Result<Vec<_>>>()?;
+
+		let mut paths = HashMap::new();
+		if header.name_table_offset != 0 {
+			input.seek(SeekFrom::Start(header.name_table_offset))?;
+			for (idx, file) in files.iter_mut().enumerate() {
+				let len = input.read_u16::<LittleEndian>()?;
+				let name = input.parse_bytes(len as usize)?;
+				let name = String::from_utf8(name.into_vec()).or(Err(Error::ParseError))?;
+				paths.insert(normalize_path(name.as_bytes()), idx);
+				file.path = Some(name);
+			}
+		}
+		Ok(Self { input, header, files, paths })
+	}
+
+	pub fn header(&self) -> &Ba2Header {
+		&self.header
+	}
+	pub fn files(&self) -> &[Ba2File] {
+		&self.files
+	}
+
+	/// index of the file with the given path, paths are case insensitive
+	/// and may use either kind of slash
+	pub fn find(&self, path: &str) -> Option<usize> {
+		self.paths.get(&normalize_path(path.as_bytes())).copied()
+	}
+
+	/// read the contents of the file at `idx`, textures get a DDS header
+	/// so the result is a complete .dds file
+	pub fn read(&mut self, idx: usize) -> Result<Vec<u8>> {
+		let file = &self.files[idx];
+		let mut result = Vec::new();
+		if let Some(texture) = &file.texture {
+			write_dds_header(&mut result, texture)?;
+		}
+		for chunk in &file.chunks {
+			self.input.seek(SeekFrom::Start(chunk.offset))?;
+			if chunk.packed_size == 0 {
+				result.extend_from_slice(&self.input.parse_bytes(chunk.unpacked_size as usize)?);
+				continue;
+			}
+			let packed = self.input.parse_bytes(chunk.packed_size as usize)?;
+			// both sizes come from the archive, don't allocate more than the
+			// packed data could inflate to
+			if chunk.unpacked_size as usize > packed.len().saturating_mul(MAX_COMPRESSION_RATIO) {
+				return Err(Error::BogusSize);
+			}
+			let unpacked = match self.header.compression {
+				Ba2Compression::Zlib => {
+					let mut unpacked = Vec::with_capacity(chunk.unpacked_size as usize);
+					ZlibDecoder::new(&*packed).take(chunk.unpacked_size as u64 + 1).read_to_end(&mut unpacked)?;
+					unpacked
+				}
+				Ba2Compression::Lz4 => {
+					lz4_flex::block::decompress(&packed, chunk.unpacked_size as usize)
+						.or(Err(Error::ParseError))?
+				}
+			};
+			if unpacked.len() != chunk.unpacked_size as usize {
+				return Err(Error::BogusSize);
+			}
+			result.extend_from_slice(&unpacked);
+		}
+		Ok(result)
+	}
+
+	/// extract every named file into `dest`, creating directories as needed
+	pub fn extract_all(&mut self, dest: &Dir) -> Result<()> {
+		for idx in 0..self.files.len() {
+			let Some(path) = &self.files[idx].path else { continue };
+			let path = relative_path(path.as_bytes());
+			if let Some(parent) = path.parent() {
+				dest.create_dir_all(parent)?;
+			}
+			let data = self.read(idx)?;
+			dest.write(path, data)?;
+		}
+		Ok(())
+	}
+}
+
+// just the formats the games actually use
+fn dxgi_block_size(format: u8) -> Option<u32> {
+	match format {
+		// BC1, BC4
+		70..=72 | 79..=81 => Some(8),
+		// BC2, BC3, BC5, BC6H, BC7
+		73..=78 | 82..=84 | 94..=99 => Some(16),
+		_ => None,
+	}
+}
+
+fn dxgi_bits_per_pixel(format: u8) -> u32 {
+	match format {
+		// R8G8B8A8, B8G8R8A8, B8G8R8X8
+		27..=32 | 87..=93 => 32,
+		// B5G6R5, B5G5R5A1, R8G8
+		85 | 86 | 48..=52 => 16,
+		// R8, A8
+		60..=65 => 8,
+		_ => 32,
+	}
+}
+
+fn write_dds_header(out: &mut impl Write, texture: &Ba2Texture) -> Result<()> {
+	const DDSD_CAPS: u32 = 0x1;
+	const DDSD_HEIGHT: u32 = 0x2;
+	const DDSD_WIDTH: u32 = 0x4;
+	const DDSD_PITCH: u32 = 0x8;
+	const DDSD_PIXELFORMAT: u32 = 0x1000;
+	const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+	const DDSD_LINEARSIZE: u32 = 0x80000;
+	const DDPF_FOURCC: u32 = 0x4;
+	const DDSCAPS_COMPLEX: u32 = 0x8;
+	const DDSCAPS_TEXTURE: u32 = 0x1000;
+	const DDSCAPS_MIPMAP: u32 = 0x400000;
+	const DDSCAPS2_CUBEMAP_ALLFACES: u32 = 0xFE00;
+	const D3D10_RESOURCE_DIMENSION_TEXTURE2D: u32 = 3;
+	const D3D10_RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+
+	let (width, height) = (texture.width as u32, texture.height as u32);
+	let mut flags = DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PIXELFORMAT | DDSD_MIPMAPCOUNT;
+	let pitch_or_linear_size = match dxgi_block_size(texture.format) {
+		Some(block_size) => {
+			flags |= DDSD_LINEARSIZE;
+			let blocks = width.div_ceil(4).max(1) as u64 * height.div_ceil(4).max(1) as u64;
+			u32::try_from(blocks * block_size as u64).or(Err(Error::BogusSize))?
+		}
+		None => {
+			flags |= DDSD_PITCH;
+			(width * dxgi_bits_per_pixel(texture.format)).div_ceil(8)
+		}
+	};
+	let mut caps = DDSCAPS_TEXTURE;
+	if texture.mip_count > 1 {
+		caps |= DDSCAPS_COMPLEX | DDSCAPS_MIPMAP;
+	}
+	let mut caps2 = 0;
+	if texture.is_cubemap() {
+		caps |= DDSCAPS_COMPLEX;
+		caps2 |= DDSCAPS2_CUBEMAP_ALLFACES;
+	}
+
+	out.write_all(b"DDS ")?;
+	out.write_u32::<LittleEndian>(124)?;
+	out.write_u32::<LittleEndian>(flags)?;
+	out.write_u32::<LittleEndian>(height)?;
+	out.write_u32::<LittleEndian>(width)?;
+	out.write_u32::<LittleEndian>(pitch_or_linear_size)?;
+	out.write_u32::<LittleEndian>(0)?; // depth
+	out.write_u32::<LittleEndian>(texture.mip_count as u32)?;
+	out.write_all(&[0; 11 * 4])?; // reserved
+	// pixel format, always described by the DX10 extension header
+	out.write_u32::<LittleEndian>(32)?;
+	out.write_u32::<LittleEndian>(DDPF_FOURCC)?;
+	out.write_all(b"DX10")?;
+	out.write_all(&[0; 5 * 4])?; // bit count and masks
+	out.write_u32::<LittleEndian>(caps)?;
+	out.write_u32::<LittleEndian>(caps2)?;
+	out.write_all(&[0; 3 * 4])?; // caps3, caps4, reserved
+	// DDS_HEADER_DXT10
+	out.write_u32::<LittleEndian>(texture.format as u32)?;
+	out.write_u32::<LittleEndian>(D3D10_RESOURCE_DIMENSION_TEXTURE2D)?;
+	out.write_u32::<LittleEndian>(if texture.is_cubemap() { D3D10_RESOURCE_MISC_TEXTURECUBE } else { 0 })?;
+	out.write_u32::<LittleEndian>(1)?; // array size
+	out.write_u32::<LittleEndian>(0)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use flate2::{write::ZlibEncoder, Compression};
+
+	use super::*;
+
+	fn header(out: &mut Vec<u8>, typ: &[u8; 4], file_count: u32) {
+		out.extend_from_slice(b"BTDX");
+		out.write_u32::<LittleEndian>(1).unwrap();
+		out.extend_from_slice(typ);
+		out.write_u32::<LittleEndian>(file_count).unwrap();
+		// name table offset, patched later
+		out.write_u64::<LittleEndian>(0).unwrap();
+	}
+
+	fn name_table(out: &mut Vec<u8>, names: &[&str]) {
+		let offset = out.len() as u64;
+		out[16..24].copy_from_slice(&offset.to_le_bytes());
+		for name in names {
+			out.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+			out.extend_from_slice(name.as_bytes());
+		}
+	}
+
+	fn zlib(data: &[u8]) -> Vec<u8> {
+		let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+		encoder.write_all(data).unwrap();
+		encoder.finish().unwrap()
+	}
+
+	#[test]
+	fn general() {
+		let raw = b"hello!";
+		let compressed = b"hello!".repeat(20);
+		let packed = zlib(&compressed);
+		let mut data = Vec::new();
+		header(&mut data, b"GNRL", 2);
+		let data_start = 24 + 2 * 36;
+		for (offset, packed_size, size) in [
+			(data_start, 0, raw.len()),
+			(data_start + raw.len(), packed.len(), compressed.len()),
+		] {
+			data.write_u32::<LittleEndian>(0).unwrap();
+			data.extend_from_slice(b"txt\0");
+			data.write_u32::<LittleEndian>(0).unwrap();
+			data.write_u32::<LittleEndian>(0x00100100).unwrap();
+			data.write_u64::<LittleEndian>(offset as u64).unwrap();
+			data.write_u32::<LittleEndian>(packed_size as u32).unwrap();
+			data.write_u32::<LittleEndian>(size as u32).unwrap();
+			data.write_u32::<LittleEndian>(0xBAADF00D).unwrap();
+		}
+		data.extend_from_slice(raw);
+		data.extend_from_slice(&packed);
+		name_table(&mut data, &["Strings\\raw.txt", "strings\\packed.txt"]);
+
+		let mut archive = Ba2Archive::new(Cursor::new(data)).unwrap();
+		assert_eq!(archive.header().typ, Ba2Type::General);
+		assert_eq!(archive.files().len(), 2);
+		let idx = archive.find("strings/RAW.txt").unwrap();
+		assert_eq!(archive.read(idx).unwrap(), raw);
+		let idx = archive.find("strings\\packed.txt").unwrap();
+		assert_eq!(archive.files()[idx].unpacked_size(), compressed.len() as u64);
+		assert_eq!(archive.read(idx).unwrap(), compressed);
+	}
+
+	#[test]
+	fn texture() {
+		// a 8x8 BC1 texture with 2 mips, split into a chunk per mip
+		let mip0 = [1u8; 32];
+		let mip1 = [2u8; 8];
+		let packed = zlib(&mip0);
+		let mut data = Vec::new();
+		header(&mut data, b"DX10", 1);
+		data.write_u32::<LittleEndian>(0).unwrap();
+		data.extend_from_slice(b"dds\0");
+		data.write_u32::<LittleEndian>(0).unwrap();
+		data.write_u8(0).unwrap();
+		data.write_u8(2).unwrap();
+		data.write_u16::<LittleEndian>(24).unwrap();
+		data.write_u16::<LittleEndian>(8).unwrap();
+		data.write_u16::<LittleEndian>(8).unwrap();
+		data.write_u8(2).unwrap();
+		data.write_u8(71).unwrap(); // BC1_UNORM
+		data.write_u8(0).unwrap();
+		data.write_u8(8).unwrap();
+		let data_start = 24 + 24 + 2 * 24;
+		for (offset, packed_size, size, mip) in [
+			(data_start, packed.len(), mip0.len(), 0),
+			(data_start + packed.len(), 0, mip1.len(), 1),
+		] {
+			data.write_u64::<LittleEndian>(offset as u64).unwrap();
+			data.write_u32::<LittleEndian>(packed_size as u32).unwrap();
+			data.write_u32::<LittleEndian>(size as u32).unwrap();
+			data.write_u16::<LittleEndian>(mip).unwrap();
+			data.write_u16::<LittleEndian>(mip).unwrap();
+			data.write_u32::<LittleEndian>(0xBAADF00D).unwrap();
+		}
+		data.extend_from_slice(&packed);
+		data.extend_from_slice(&mip1);
+		name_table(&mut data, &["textures\\test.dds"]);
+
+		let mut archive = Ba2Archive::new(Cursor::new(data)).unwrap();
+		let dds = archive.read(archive.find("textures\\test.dds").unwrap()).unwrap();
+		assert_eq!(dds.len(), 4 + 124 + 20 + 40);
+		assert_eq!(&dds[0..4], b"DDS ");
+		// height, width and linear size
+		assert_eq!(&dds[12..20], &[8, 0, 0, 0, 8, 0, 0, 0]);
+		assert_eq!(u32::from_le_bytes(dds[20..24].try_into().unwrap()), 32);
+		assert_eq!(&dds[84..88], b"DX10");
+		assert_eq!(u32::from_le_bytes(dds[128..132].try_into().unwrap()), 71);
+		assert_eq!(&dds[148..180], &mip0);
+		assert_eq!(&dds[180..], &mip1);
+
+		let dest = cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+		archive.extract_all(&dest).unwrap();
+		assert_eq!(dest.read("textures/test.dds").unwrap(), dds);
+	}
+
+	#[test]
+	fn huge_texture() {
+		let texture = |format| Ba2Texture { height: u16::MAX, width: u16::MAX, mip_count: 1, format, flags: 0, tile_mode: 8 };
+		// BC7 at this size doesn't fit the header's u32 linear size
+		assert!(matches!(write_dds_header(&mut Vec::new(), &texture(98)), Err(Error::BogusSize)));
+		let mut dds = Vec::new();
+		write_dds_header(&mut dds, &texture(71)).unwrap();
+		assert_eq!(u32::from_le_bytes(dds[20..24].try_into().unwrap()), 16384 * 16384 * 8);
+	}
+}
@@ -7,18 +7,28 @@
 
 
 
-use std::ffi::{CString, FromBytesWithNulError};
+use std::ffi::{CStr, CString, FromBytesWithNulError};
 use std::fmt::Debug;
-use std::io::{self, BorrowedBuf, Read};
+use std::fs::File;
+use std::io::{self, BorrowedBuf, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::mem::{MaybeUninit, transmute};
 
 use bitflags::bitflags;
 use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt};
 use bytes::Buf;
+use cap_std::fs::Dir;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
 use thiserror::Error;
 
 use crate::common::*;
 
+pub mod mmap;
+pub mod writer;
+pub use mmap::MmapArchive;
+pub use writer::BsaWriter;
+
 
 
 bitflags! {
@@ -94,8 +104,8 @@ impl SeekPredicate for std::io::BufReader<std::fs::File> {}
 impl ParseBsa for ArchiveHeader {
 	fn parse(input: &mut impl Read) -> Result<Self> {
 		use Error::*;
-		let mut buf: [MaybeUninit<u8>; Self::SIZE as usize]
-			= MaybeUninit::uninit_array();
+		let mut buf: [MaybeUninit<u8>; Self::SIZE]
+			= [const { MaybeUninit::uninit() }; Self::SIZE];
 		let mut buf = BorrowedBuf::from(&mut buf[..]);
 		input.read_buf_exact(buf.unfilled())?;
 		let mut buf = buf.filled();
@@ -137,6 +147,10 @@ fn test_archive_reader() {
 
 	let file_data = (&test1_f).parse_bytes(file.size as usize);
 	println!("{:?}", file_data);
+
+	let file_data = file.read_data(&mut test1_f, &reader).unwrap();
+	assert!(file_data.name.is_none());
+	assert_eq!(&*file_data.data, b"hello!");
 }
 
 
@@ -172,6 +186,15 @@ pub struct ArchiveHeader {
 
 impl ConstantSizedRecord for ArchiveHeader { const SIZE: usize = 36; }
 
+impl ArchiveHeader {
+	/// whether each file's data is prefixed with its full path as a bstring
+	pub fn embeds_file_names(&self) -> bool {
+		// oblivion uses this bit for something else
+		!matches!(self.version, ArchiveVersion::Oblivion)
+			&& self.flags.contains(ArchiveFlags::EMBED_FILE_NAMES)
+	}
+}
+
 #[repr(C)]
 pub struct RawFolderRecord105 {
 	pub hash: u64,
@@ -253,6 +276,8 @@ impl FolderRecord {
 	}
 }
 
+impl ConstantSizedRecord for FileRecord { const SIZE: usize = 16; }
+
 impl SizedRecord for FolderRecord {
 	fn size(ver: ArchiveVersion) -> usize {
 		// there's padding from pointers inside this field,
@@ -266,6 +291,10 @@ impl SizedRecord for FolderRecord {
 }
 
 impl FileRecord {
+	/// flips the archive's default compression for this file
+	pub const COMPRESSION_TOGGLE: u32 = 1 << 30;
+	pub const SIZE_MASK: u32 = !(Self::COMPRESSION_TOGGLE | 1 << 31);
+
 	fn parse_given(input: &mut impl Read, header: &ArchiveHeader) -> Result<Self> {
 		match header.flags {
 			ArchiveFlags::XBOX360_ARCHIVE => Self::parse_endian::<BigEndian>(input),
@@ -280,24 +309,597 @@ impl FileRecord {
 		})
 	}
 
-	fn is_compressed(&self, header: &ArchiveHeader) -> bool {
+	pub fn is_compressed(&self, header: &ArchiveHeader) -> bool {
 		header.flags.contains(ArchiveFlags::COMPRESSED_ARCHIVE)
-			^ (self.size & 0x40000000 != 0)
+			^ (self.size & Self::COMPRESSION_TOGGLE != 0)
+	}
+
+	/// size of the file's data block, without the flag bits
+	pub fn data_size(&self) -> u32 {
+		self.size & Self::SIZE_MASK
+	}
+
+	/// reads and, if needed, decompresses this file's data from an archive
+	pub fn read_data<R: Read + Seek>(&self, input: &mut R, header: &ArchiveHeader) -> Result<FileData> {
+		input.seek(SeekFrom::Start(self.offset as u64))?;
+		let mut remaining = self.data_size() as usize;
+		let name = if header.embeds_file_names() {
+			let name = input.parse_bstring()?;
+			remaining = remaining.checked_sub(name.len() + 1).ok_or(Error::BogusSize)?;
+			Some(name)
+		} else {
+			None
+		};
+		let data = if self.is_compressed(header) {
+			let original_size = input.read_u32::<LittleEndian>()?;
+			remaining = remaining.checked_sub(4).ok_or(Error::BogusSize)?;
+			let compressed = input.parse_bytes(remaining)?;
+			decompress(header.version, &compressed, original_size as usize)?
+		} else {
+			input.parse_bytes(remaining)?
+		};
+		Ok(FileData { name, data })
+	}
+}
+
+/// The contents of a file in an archive
+#[derive(Debug)]
+pub struct FileData {
+	/// full path of the file, only present if the archive embeds file names
+	pub name: Option<Box<[u8]>>,
+	pub data: Box<[u8]>,
+}
+
+/// neither zlib nor lz4 can compress better than about 1032:1, so a stored
+/// size bigger than this many times the compressed size is bogus
+pub(crate) const MAX_COMPRESSION_RATIO: usize = 1032;
+
+/// decompress a single file block, oblivion and skyrim use zlib streams
+/// and skyrim special edition uses lz4 frames
+pub fn decompress(ver: ArchiveVersion, input: &[u8], original_size: usize) -> Result<Box<[u8]>> {
+	// the size comes from the archive, don't trust it for more than the data
+	// could possibly inflate to
+	let mut result = Vec::with_capacity(original_size.min(input.len().saturating_mul(MAX_COMPRESSION_RATIO)));
+	// one more byte than expected is enough to tell the size is wrong
+	let limit = original_size as u64 + 1;
+	match ver {
+		ArchiveVersion::Oblivion | ArchiveVersion::Skyrim => {
+			ZlibDecoder::new(input).take(limit).read_to_end(&mut result)?
+		}
+		ArchiveVersion::SkyrimSE => FrameDecoder::new(input).take(limit).read_to_end(&mut result)?,
+	};
+	if result.len() != original_size {
+		return Err(Error::BogusSize);
+	}
+	Ok(result.into_boxed_slice())
+}
+
+/// compress a single file block with the codec `ver` expects, see [decompress]
+pub fn compress(ver: ArchiveVersion, input: &[u8]) -> Result<Vec<u8>> {
+	Ok(match ver {
+		ArchiveVersion::Oblivion | ArchiveVersion::Skyrim => {
+			let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+			encoder.write_all(input)?;
+			encoder.finish()?
+		}
+		ArchiveVersion::SkyrimSE => {
+			let mut encoder = FrameEncoder::new(Vec::new());
+			encoder.write_all(input)?;
+			encoder.finish().map_err(io::Error::from)?
+		}
+	})
+}
+
+#[test]
+fn test_decompress() {
+	let content = b"hello!hello!hello!hello!";
+
+	let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+	zlib.write_all(content).unwrap();
+	let zlib = zlib.finish().unwrap();
+	assert_eq!(&*decompress(ArchiveVersion::Skyrim, &zlib, content.len()).unwrap(), content);
+
+	let mut lz4 = FrameEncoder::new(Vec::new());
+	lz4.write_all(content).unwrap();
+	let lz4 = lz4.finish().unwrap();
+	assert_eq!(&*decompress(ArchiveVersion::SkyrimSE, &lz4, content.len()).unwrap(), content);
+	assert!(decompress(ArchiveVersion::SkyrimSE, &lz4, content.len() + 1).is_err());
+	assert!(decompress(ArchiveVersion::SkyrimSE, &lz4, content.len() - 1).is_err());
+	// a huge claimed size fails without allocating it up front
+	assert!(decompress(ArchiveVersion::Skyrim, &zlib, u32::MAX as usize).is_err());
+
+	for ver in [ArchiveVersion::Oblivion, ArchiveVersion::SkyrimSE] {
+		let compressed = compress(ver, content).unwrap();
+		assert_eq!(&*decompress(ver, &compressed, content.len()).unwrap(), content);
 	}
 }
+#[derive(Debug)]
 pub struct IndexedFolder {
-	pub name: CString,
+	/// only present if the archive includes directory names
+	pub name: Option<CString>,
 	pub hash: u64,
-	pub first_file_idx: u32
+	pub first_file_idx: u32,
+	pub file_count: u32,
 }
+
+#[derive(Debug)]
 pub struct IndexedFile {
+	/// full virtual path, i.e. `meshes\foo.nif`, only present if the archive
+	/// includes both directory and file names
 	pub path: Option<CString>,
-	pub data_size: u64,
+	pub folder_idx: u32,
+	pub record: FileRecord,
+}
+
+impl IndexedFile {
+	/// size of the file's data block as stored in the archive
+	pub fn data_size(&self) -> u64 {
+		self.record.data_size() as u64
+	}
+	pub fn offset(&self) -> u64 {
+		self.record.offset as u64
+	}
+}
+
+/// The full folder and file tables of an archive, along with the archive itself
+/// so file contents can be read on demand
+pub struct IndexedArchive<R: Read + Seek> {
+	input: R,
+	header: ArchiveHeader,
+	folders: Vec<IndexedFolder>,
+	files: Vec<IndexedFile>,
+}
+
+impl IndexedArchive<BufReader<File>> {
+	pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+		Self::new(BufReader::new(File::open(path)?))
+	}
+}
+
+impl<R: Read + Seek> IndexedArchive<R> {
+	pub fn new(mut input: R) -> Result<Self> {
+		input.seek(SeekFrom::Start(0))?;
+		let header = ArchiveHeader::parse(&mut input)?;
+		let folder_records = (0..header.folder_count)
+			.map(|_| FolderRecord::parse_given(&mut input, &header))
+			.collect::<Result<Vec<_>>>()?;
+
+		let mut folders = Vec::with_capacity(folder_records.len());
+		// the count in the header isn't trusted, files are added as their records are read
+		let mut files = Vec::new();
+		// each folder's name and file records follow the folder records, in the same order
+		for (folder_idx, folder) in folder_records.iter().enumerate() {
+			let name = if header.flags.contains(ArchiveFlags::INCLUDE_DIR_NAMES) {
+				Some(input.parse_bzstring()?)
+			} else {
+				None
+			};
+			folders.push(IndexedFolder {
+				name,
+				hash: folder.hash,
+				first_file_idx: files.len() as u32,
+				file_count: folder.count,
+			});
+			for _ in 0..folder.count {
+				files.push(IndexedFile {
+					path: None,
+					folder_idx: folder_idx as u32,
+					record: FileRecord::parse_given(&mut input, &header)?,
+				});
+			}
+		}
+		if files.len() != header.file_count as usize {
+			return Err(Error::BogusSize);
+		}
+
+		if header.flags.contains(ArchiveFlags::INCLUDE_FILE_NAMES) {
+			let len = header.total_file_name_length as u64;
+			let pos = input.stream_position()?;
+			if input.seek(SeekFrom::End(0))?.saturating_sub(pos) < len {
+				return Err(Error::BogusSize);
+			}
+			input.seek(SeekFrom::Start(pos))?;
+			let names = input.parse_bytes(len as usize)?;
+			let mut names = names.split_inclusive(|&c| c == 0);
+			for file in files.iter_mut() {
+				let name = names.next().ok_or(Error::BogusSize)?;
+				let name = CStr::from_bytes_with_nul(name).or(Err(Error::ParseError))?;
+				let Some(folder) = &folders[file.folder_idx as usize].name else {
+					continue;
+				};
+				let path = virtual_path(folder.to_bytes(), name.to_bytes());
+				file.path = Some(CString::new(path).or(Err(Error::ParseError))?);
+			}
+		}
+
+		Ok(Self { input, header, folders, files })
+	}
+
+	pub fn header(&self) -> &ArchiveHeader {
+		&self.header
+	}
+	pub fn folders(&self) -> &[IndexedFolder] {
+		&self.folders
+	}
+	pub fn files(&self) -> &[IndexedFile] {
+		&self.files
+	}
+
+	/// index of the file with the given virtual path, paths are case insensitive
+	/// and may use either kind of slash.
+	///
+	/// This goes by hash alone, like the games do, so it works even if
+	/// the archive doesn't include names.
+	pub fn find(&self, path: &str) -> Option<usize> {
+		let path = normalize_path(path.as_bytes());
+		let (folder, name) = split_path(&path);
+		let folder_idx = self
+			.folders
+			.binary_search_by_key(&folder_hash(folder), |f| f.hash)
+			.ok()?;
+		let folder = &self.folders[folder_idx];
+		let first = folder.first_file_idx as usize;
+		let files = &self.files[first..first + folder.file_count as usize];
+		let idx = files.binary_search_by_key(&file_hash(name), |f| f.record.hash).ok()?;
+		Some(first + idx)
+	}
+	pub fn get(&self, path: &str) -> Option<&IndexedFile> {
+		self.find(path).map(|idx| &self.files[idx])
+	}
+
+	/// read the contents of the file at `idx`
+	pub fn read(&mut self, idx: usize) -> Result<FileData> {
+		self.files[idx].record.read_data(&mut self.input, &self.header)
+	}
+
+	/// extract every named file into `dest`, creating directories as needed
+	pub fn extract_all(&mut self, dest: &Dir) -> Result<()> {
+		for idx in 0..self.files.len() {
+			let Some(path) = &self.files[idx].path else { continue };
+			let path = relative_path(path.to_bytes());
+			if let Some(parent) = path.parent() {
+				dest.create_dir_all(parent)?;
+			}
+			let data = self.read(idx)?;
+			dest.write(path, data.data)?;
+		}
+		Ok(())
+	}
 
+	/// recompute the hash of every named folder and file and compare against the
+	/// stored hashes, an empty result means the archive is consistent
+	pub fn verify(&self) -> Vec<HashMismatch> {
+		let mut result = Vec::new();
+		for (idx, folder) in self.folders.iter().enumerate() {
+			let Some(name) = &folder.name else { continue };
+			let computed = folder_hash(name.to_bytes());
+			if computed != folder.hash {
+				result.push(HashMismatch::Folder { idx, stored: folder.hash, computed });
+			}
+		}
+		for (idx, file) in self.files.iter().enumerate() {
+			let Some(path) = &file.path else { continue };
+			let (_, name) = split_path(path.to_bytes());
+			let computed = file_hash(name);
+			if computed != file.record.hash {
+				result.push(HashMismatch::File { idx, stored: file.record.hash, computed });
+			}
+		}
+		result
+	}
+}
+
+/// Reads single files out of an archive without building an index, for when
+/// only a few lookups are needed
+pub struct BsaReader<R: Read + Seek> {
+	input: R,
+	header: ArchiveHeader,
+}
+
+impl BsaReader<BufReader<File>> {
+	pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+		Self::new(BufReader::new(File::open(path)?))
+	}
+}
+
+impl<R: Read + Seek> BsaReader<R> {
+	pub fn new(mut input: R) -> Result<Self> {
+		input.seek(SeekFrom::Start(0))?;
+		let header = ArchiveHeader::parse(&mut input)?;
+		Ok(Self { input, header })
+	}
+
+	pub fn header(&self) -> &ArchiveHeader {
+		&self.header
+	}
+
+	fn folder_record(&mut self, idx: u32) -> Result<FolderRecord> {
+		let ver = self.header.version;
+		let pos = self.header.offset as u64 + idx as u64 * FolderRecord::size(ver) as u64;
+		self.input.seek(SeekFrom::Start(pos))?;
+		FolderRecord::parse_given(&mut self.input, &self.header)
+	}
+
+	/// offset of the first file record of `folder`
+	fn file_block(&mut self, folder: &FolderRecord) -> Result<u64> {
+		// folder offsets count the file name table as if it came before them
+		let pos = (folder.offset as u64)
+			.checked_sub(self.header.total_file_name_length as u64)
+			.ok_or(Error::BogusSize)?;
+		if !self.header.flags.contains(ArchiveFlags::INCLUDE_DIR_NAMES) {
+			return Ok(pos);
+		}
+		self.input.seek(SeekFrom::Start(pos))?;
+		let name_len = self.input.read_u8()?;
+		Ok(pos + 1 + name_len as u64)
+	}
+
+	/// Find the record of the file with the given virtual path by binary
+	/// searching the folder records, then the folder's file records, straight
+	/// from the archive. Like [IndexedArchive::find] this only uses hashes.
+	pub fn find(&mut self, path: &str) -> Result<Option<FileRecord>> {
+		let (folder_hash, file_hash) = path_hash(path.as_bytes());
+		let Some(folder) = binary_search(self.header.folder_count, folder_hash, |idx| {
+			let folder = self.folder_record(idx)?;
+			Ok((folder.hash, folder))
+		})?
+		else {
+			return Ok(None);
+		};
+		let block = self.file_block(&folder)?;
+		let ver = self.header.version;
+		binary_search(folder.count, file_hash, |idx| {
+			self.input.seek(SeekFrom::Start(block + idx as u64 * FileRecord::size(ver) as u64))?;
+			let file = FileRecord::parse_given(&mut self.input, &self.header)?;
+			Ok((file.hash, file))
+		})
+	}
+
+	pub fn read(&mut self, file: &FileRecord) -> Result<FileData> {
+		file.read_data(&mut self.input, &self.header)
+	}
+
+	/// read the file with the given virtual path, if the archive has it
+	pub fn get(&mut self, path: &str) -> Result<Option<FileData>> {
+		match self.find(path)? {
+			Some(file) => self.read(&file).map(Some),
+			None => Ok(None),
+		}
+	}
+}
+
+/// binary search `count` records sorted by hash, `get` reads the hash and
+/// record at an index
+fn binary_search<T>(count: u32, hash: u64, mut get: impl FnMut(u32) -> Result<(u64, T)>) -> Result<Option<T>> {
+	let (mut low, mut high) = (0, count);
+	while low < high {
+		let mid = low + (high - low) / 2;
+		let (mid_hash, record) = get(mid)?;
+		match mid_hash.cmp(&hash) {
+			std::cmp::Ordering::Less => low = mid + 1,
+			std::cmp::Ordering::Greater => high = mid,
+			std::cmp::Ordering::Equal => return Ok(Some(record)),
+		}
+	}
+	Ok(None)
+}
+
+#[test]
+fn test_bsa_reader() {
+	use std::io::Cursor;
+	let test1_p = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/test1.bsa");
+	let mut reader = BsaReader::open(&test1_p).unwrap();
+	for path in ["test.txt", "TestFolder/test.txt", "testfolder\\testnestedfolder\\test.txt"] {
+		assert_eq!(&*reader.get(path).unwrap().unwrap().data, b"hello!");
+	}
+	assert!(reader.find("testfolder\\missing.txt").unwrap().is_none());
+	assert!(reader.find("missing\\test.txt").unwrap().is_none());
+
+	// agrees with the index on the 64-bit layout, with and without names
+	let content = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/test1_content");
+	for ver in [ArchiveVersion::Skyrim, ArchiveVersion::SkyrimSE] {
+		let archive = |names| {
+			let mut writer = BsaWriter::new(ver).include_names(names);
+			writer.add_dir(&content).unwrap();
+			for idx in 0..20 {
+				writer.add(&format!("meshes/{idx}.nif"), &b"mesh"[..]).unwrap();
+			}
+			let mut out = Cursor::new(Vec::new());
+			writer.write(&mut out).unwrap();
+			out
+		};
+		// the paths to look up come from the archive with names
+		let named = IndexedArchive::new(archive(true)).unwrap();
+		for names in [true, false] {
+			let out = archive(names);
+			let mut reader = BsaReader::new(Cursor::new(out.get_ref().clone())).unwrap();
+			let archive = IndexedArchive::new(out).unwrap();
+			assert_eq!(archive.header().flags.contains(ArchiveFlags::INCLUDE_DIR_NAMES), names);
+			assert_eq!(archive.files().len(), named.files().len());
+			for (file, named_file) in archive.files().iter().zip(named.files()) {
+				assert_eq!(file.path.is_some(), names);
+				let path = named_file.path.as_ref().unwrap().to_str().unwrap();
+				assert_eq!(reader.find(path).unwrap().unwrap().offset, file.record.offset);
+			}
+			assert_eq!(&*reader.get("meshes\\3.nif").unwrap().unwrap().data, b"mesh");
+			assert!(reader.find("meshes\\20.nif").unwrap().is_none());
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HashMismatch {
+	Folder { idx: usize, stored: u64, computed: u64 },
+	File { idx: usize, stored: u64, computed: u64 },
+}
+
+/// split a virtual path into its folder and file name
+fn split_path(path: &[u8]) -> (&[u8], &[u8]) {
+	match path.iter().rposition(|&c| c == b'\\' || c == b'/') {
+		Some(pos) => (&path[..pos], &path[pos + 1..]),
+		None => (b".", path),
+	}
+}
+
+fn hash_part(data: &[u8]) -> u32 {
+	data.iter()
+		.fold(0u32, |hash, &c| hash.wrapping_mul(0x1003f).wrapping_add(c as u32))
+}
+
+fn hash_stem(stem: &[u8]) -> u64 {
+	let len = stem.len();
+	if len == 0 {
+		return 0;
+	}
+	let mut result = stem[len - 1] as u64
+		| (len as u64) << 16
+		| (stem[0] as u64) << 24;
+	if len >= 3 {
+		result |= (stem[len - 2] as u64) << 8;
+	}
+	if len >= 4 {
+		result = result.wrapping_add((hash_part(&stem[1..len - 2]) as u64) << 32);
+	}
+	result
+}
+
+/// hash of a folder path as used by tes4 and later archives, i.e. `meshes\armor`
+pub fn folder_hash(name: &[u8]) -> u64 {
+	hash_stem(&normalize_path(name))
+}
+
+/// hash of a file name (without its folder) as used by tes4 and later archives
+pub fn file_hash(name: &[u8]) -> u64 {
+	let name = normalize_path(name);
+	let (stem, ext) = match name.iter().rposition(|&c| c == b'.') {
+		Some(pos) => name.split_at(pos),
+		None => (&name[..], &[][..]),
+	};
+	let mut result = hash_stem(stem);
+	if ext.is_empty() {
+		return result;
+	}
+	result |= match ext {
+		b".kf" => 0x80,
+		b".nif" => 0x8000,
+		b".dds" => 0x8080,
+		b".wav" => 0x80000000,
+		_ => 0,
+	};
+	result.wrapping_add((hash_part(ext) as u64) << 32)
+}
+
+/// folder and file hashes of a full virtual path, i.e. `meshes\armor\foo.nif`
+pub fn path_hash(path: &[u8]) -> (u64, u64) {
+	let path = normalize_path(path);
+	let (folder, name) = split_path(&path);
+	(folder_hash(folder), file_hash(name))
+}
+
+#[test]
+fn test_hashes() {
+	assert_eq!(folder_hash(b"."), 0x2e01002e);
+	let test1_p = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/test1.bsa");
+	let archive = IndexedArchive::open(test1_p).unwrap();
+	assert_eq!(archive.verify(), []);
+	assert!(archive.folders().is_sorted_by_key(|f| f.hash));
+	assert_eq!(folder_hash(b"TestFolder/TestNestedFolder"), folder_hash(b"testfolder\\testnestedfolder"));
+	let file = &archive.files()[0];
+	let folder = &archive.folders()[file.folder_idx as usize];
+	let path = file.path.as_ref().unwrap().to_bytes();
+	assert_eq!(path_hash(path), (folder.hash, file.record.hash));
+}
+
+#[test]
+fn test_verify_corrupt_names() {
+	use std::io::Cursor;
+	let test1_p = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/test1.bsa");
+	let mut data = std::fs::read(test1_p).unwrap();
+	// rename the first entry in the file name table from test.txt to tesu.txt
+	let names_start = data.windows(9).position(|w| w == b"test.txt\0").unwrap();
+	data[names_start + 3] = b'u';
+	let archive = IndexedArchive::new(Cursor::new(data)).unwrap();
+	let mismatches = archive.verify();
+	assert_eq!(mismatches.len(), 1);
+	assert!(matches!(mismatches[0], HashMismatch::File { idx: 0, .. }));
+}
+
+/// a virtual path as a relative filesystem path, names that aren't utf-8
+/// are converted lossily
+pub(crate) fn relative_path(path: &[u8]) -> PathBuf {
+	PathBuf::from(String::from_utf8_lossy(path).replace('\\', "/"))
+}
+
+fn virtual_path(folder: &[u8], name: &[u8]) -> Vec<u8> {
+	// files in the root of the archive are in the "." folder
+	if folder == b"." || folder.is_empty() {
+		return name.to_vec();
+	}
+	let mut path = Vec::with_capacity(folder.len() + name.len() + 1);
+	path.extend_from_slice(folder);
+	path.push(b'\\');
+	path.extend_from_slice(name);
+	path
+}
+
+pub(crate) fn normalize_path(path: &[u8]) -> Box<[u8]> {
+	path.iter()
+		.map(|c| match c {
+			b'/' => b'\\',
+			c => c.to_ascii_lowercase(),
+		})
+		.skip_while(|&c| c == b'\\')
+		.collect()
+}
+
+#[test]
+fn test_indexed_archive() {
+	let test1_p = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/test1.bsa");
+	let mut archive = IndexedArchive::open(test1_p).unwrap();
+	assert_eq!(archive.folders().len(), 3);
+	assert_eq!(archive.files().len(), 3);
+	let mut paths: Vec<_> = archive
+		.files()
+		.iter()
+		.map(|f| f.path.as_ref().unwrap().to_str().unwrap())
+		.collect();
+	paths.sort();
+	assert_eq!(paths, [
+		"test.txt",
+		"testfolder\\test.txt",
+		"testfolder\\testnestedfolder\\test.txt"
+	]);
+	assert!(archive.files().iter().all(|f| f.data_size() == 6));
+
+	let idx = archive.find("TestFolder/TestNestedFolder/test.txt").unwrap();
+	assert_eq!(&*archive.read(idx).unwrap().data, b"hello!");
+	assert!(archive.get("testfolder\\missing.txt").is_none());
+
+	let dest = cap_tempfile::tempdir(cap_std::ambient_authority()).unwrap();
+	archive.extract_all(&dest).unwrap();
+	assert_eq!(dest.read_to_string("test.txt").unwrap(), "hello!");
+	assert_eq!(dest.read_to_string("testfolder/testnestedfolder/test.txt").unwrap(), "hello!");
+
+	// counts and lengths from the header are checked against what's there
+	// before anything is allocated for them
+	let header = |file_count, total_file_name_length| {
+		let mut out = Vec::new();
+		ArchiveHeader {
+			tag: *b"BSA\0",
+			version: ArchiveVersion::SkyrimSE,
+			offset: ArchiveHeader::SIZE as u32,
+			flags: ArchiveFlags::INCLUDE_FILE_NAMES,
+			folder_count: 0,
+			file_count,
+			total_folder_name_length: 0,
+			total_file_name_length,
+			file_flags: FileFlags::empty(),
+		}
+		.write(&mut out)
+		.unwrap();
+		io::Cursor::new(out)
+	};
+	assert!(matches!(IndexedArchive::new(header(u32::MAX, 0)), Err(Error::BogusSize)));
+	assert!(matches!(IndexedArchive::new(header(0, u32::MAX)), Err(Error::BogusSize)));
+	assert!(IndexedArchive::new(header(0, 0)).unwrap().files().is_empty());
 }
-// pub struct IndexedArchive {
-// 	header: ArchiveHeader,
-// 	file_hashes: Vec<u64>,
-// 	file_names: Vec<CString>,
-// 	folders: Vec<IndexedFolder>,
-// }
@@ -0,0 +1,149 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use super::*;
+
+/// An archive that's memory mapped rather than read, stored files are handed
+/// out as slices of the mapping and compressed files are only decompressed
+/// when their data is asked for.
+pub struct MmapArchive {
+	index: IndexedArchive<Cursor<Mmap>>,
+}
+
+/// A single file in a [MmapArchive]
+#[derive(Debug, Clone, Copy)]
+pub struct MmapEntry<'a> {
+	/// full path of the file, only present if the archive embeds file names
+	pub name: Option<&'a [u8]>,
+	version: ArchiveVersion,
+	/// size after decompression, if the file is compressed
+	original_size: Option<u32>,
+	raw: &'a [u8],
+}
+
+impl MmapArchive {
+	pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+		let file = File::open(path)?;
+		// Safety: the usual mmap caveat, the archive must not be modified while it's mapped
+		Self::new(unsafe { Mmap::map(&file)? })
+	}
+
+	pub fn new(mmap: Mmap) -> Result<Self> {
+		Ok(Self { index: IndexedArchive::new(Cursor::new(mmap))? })
+	}
+
+	pub fn header(&self) -> &ArchiveHeader {
+		self.index.header()
+	}
+	pub fn folders(&self) -> &[IndexedFolder] {
+		self.index.folders()
+	}
+	pub fn files(&self) -> &[IndexedFile] {
+		self.index.files()
+	}
+	/// see [IndexedArchive::find]
+	pub fn find(&self, path: &str) -> Option<usize> {
+		self.index.find(path)
+	}
+	/// see [IndexedArchive::verify]
+	pub fn verify(&self) -> Vec<HashMismatch> {
+		self.index.verify()
+	}
+
+	/// the file at `idx`, without touching its data
+	pub fn entry(&self, idx: usize) -> Result<MmapEntry<'_>> {
+		let header = self.header();
+		let record = &self.files()[idx].record;
+		let map: &[u8] = self.index.input.get_ref();
+		let start = record.offset as usize;
+		let mut block = start
+			.checked_add(record.data_size() as usize)
+			.and_then(|end| map.get(start..end))
+			.ok_or(Error::BogusSize)?;
+		let name = if header.embeds_file_names() {
+			let (&len, rest) = block.split_first().ok_or(Error::BogusSize)?;
+			let (name, rest) = rest.split_at_checked(len as usize).ok_or(Error::BogusSize)?;
+			block = rest;
+			Some(name)
+		} else {
+			None
+		};
+		let original_size = if record.is_compressed(header) {
+			let (size, rest) = block.split_first_chunk::<4>().ok_or(Error::BogusSize)?;
+			block = rest;
+			Some(u32::from_le_bytes(*size))
+		} else {
+			None
+		};
+		Ok(MmapEntry { name, version: header.version, original_size, raw: block })
+	}
+
+	pub fn entries(&self) -> impl Iterator<Item = Result<MmapEntry<'_>>> {
+		(0..self.files().len()).map(|idx| self.entry(idx))
+	}
+}
+
+impl<'a> MmapEntry<'a> {
+	pub fn is_compressed(&self) -> bool {
+		self.original_size.is_some()
+	}
+
+	/// size of the file's contents, after any decompression
+	pub fn size(&self) -> usize {
+		self.original_size.map_or(self.raw.len(), |size| size as usize)
+	}
+
+	/// the file's data as stored in the archive, still compressed if the file is
+	pub fn raw(&self) -> &'a [u8] {
+		self.raw
+	}
+
+	/// the file's contents, borrowed straight from the mapping unless the
+	/// file had to be decompressed
+	pub fn data(&self) -> Result<Cow<'a, [u8]>> {
+		match self.original_size {
+			None => Ok(Cow::Borrowed(self.raw)),
+			Some(size) => Ok(Cow::Owned(decompress(self.version, self.raw, size as usize)?.into_vec())),
+		}
+	}
+}
+
+#[test]
+fn test_mmap_archive() {
+	use memmap2::MmapMut;
+	use std::io::Write;
+
+	let test1_p = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/test1.bsa");
+	let archive = MmapArchive::open(test1_p).unwrap();
+	assert_eq!(archive.verify(), []);
+	for entry in archive.entries() {
+		let entry = entry.unwrap();
+		assert!(!entry.is_compressed());
+		assert!(matches!(entry.data().unwrap(), Cow::Borrowed(b"hello!")));
+	}
+
+	let big = b"hello!".repeat(100);
+	let mut writer = BsaWriter::new(ArchiveVersion::SkyrimSE).compressed(true).embed_file_names(true);
+	writer.add("meshes\\big.nif", &big[..]).unwrap();
+	let mut out = Cursor::new(Vec::new());
+	writer.write(&mut out).unwrap();
+	let out = out.into_inner();
+	let mut mmap = MmapMut::map_anon(out.len()).unwrap();
+	(&mut mmap[..]).write_all(&out).unwrap();
+
+	let archive = MmapArchive::new(mmap.make_read_only().unwrap()).unwrap();
+	let entry = archive.entry(archive.find("meshes/big.nif").unwrap()).unwrap();
+	assert_eq!(entry.name, Some(&b"meshes\\big.nif"[..]));
+	assert!(entry.is_compressed());
+	assert_eq!(entry.size(), big.len());
+	assert!(entry.raw().len() < big.len());
+	assert_eq!(*entry.data().unwrap(), big[..]);
+}
@@ -0,0 +1,368 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use super::*;
+
+struct PendingFile<'a> {
+	name: Box<[u8]>,
+	source: Box<dyn Read + 'a>,
+}
+
+struct PendingFolder<'a> {
+	name: Box<[u8]>,
+	files: BTreeMap<u64, PendingFile<'a>>,
+}
+
+/// Packs a set of files into a new archive.
+///
+/// Folders and files are sorted by hash as the games expect, file contents are
+/// only read once the archive is actually written.
+pub struct BsaWriter<'a> {
+	version: ArchiveVersion,
+	compressed: bool,
+	embed_file_names: bool,
+	include_names: bool,
+	folders: BTreeMap<u64, PendingFolder<'a>>,
+}
+
+// opens the file on first read, so packing a large tree doesn't
+// hold every file open at once
+struct LazyFile {
+	path: PathBuf,
+	file: Option<File>,
+}
+
+impl Read for LazyFile {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.file.is_none() {
+			self.file = Some(File::open(&self.path)?);
+		}
+		self.file.as_mut().unwrap().read(buf)
+	}
+}
+
+impl<'a> BsaWriter<'a> {
+	pub fn new(version: ArchiveVersion) -> Self {
+		Self {
+			version,
+			compressed: false,
+			embed_file_names: false,
+			include_names: true,
+			folders: BTreeMap::new(),
+		}
+	}
+
+	/// compress file data, with zlib for oblivion and skyrim and lz4 for skyrim special edition
+	pub fn compressed(mut self, compressed: bool) -> Self {
+		self.compressed = compressed;
+		self
+	}
+
+	/// prefix each file's data with its full path, ignored for oblivion archives
+	pub fn embed_file_names(mut self, embed: bool) -> Self {
+		self.embed_file_names = embed;
+		self
+	}
+
+	/// store folder and file names, without them files can only be looked up
+	/// by hash. On by default
+	pub fn include_names(mut self, include: bool) -> Self {
+		self.include_names = include;
+		self
+	}
+
+	/// add a file at the virtual path `path`, i.e. `meshes\foo.nif`
+	pub fn add(&mut self, path: &str, source: impl Read + 'a) -> Result<()> {
+		let path = normalize_path(path.as_bytes());
+		let (folder_name, name) = split_path(&path);
+		if name.is_empty() {
+			return Err(Error::ParseError);
+		}
+		let folder = self
+			.folders
+			.entry(folder_hash(folder_name))
+			.or_insert_with(|| PendingFolder {
+				name: folder_name.into(),
+				files: BTreeMap::new(),
+			});
+		// two different names that happen to hash the same can't both be stored
+		if *folder.name != *folder_name || folder.files.contains_key(&file_hash(name)) {
+			return Err(Error::DuplicateEntry);
+		}
+		folder.files.insert(file_hash(name), PendingFile {
+			name: name.into(),
+			source: Box::new(source),
+		});
+		Ok(())
+	}
+
+	/// add every file under `root`, with paths relative to `root`
+	pub fn add_dir(&mut self, root: impl AsRef<Path>) -> Result<()> {
+		self.add_dir_prefixed(root.as_ref(), "")
+	}
+
+	fn add_dir_prefixed(&mut self, dir: &Path, prefix: &str) -> Result<()> {
+		for entry in fs::read_dir(dir)? {
+			let entry = entry?;
+			let file_name = entry.file_name();
+			let file_name = file_name.to_str().ok_or(Error::ParseError)?;
+			let path = if prefix.is_empty() {
+				file_name.to_owned()
+			} else {
+				format!("{prefix}\\{file_name}")
+			};
+			let file_type = entry.file_type()?;
+			if file_type.is_dir() {
+				self.add_dir_prefixed(&entry.path(), &path)?;
+			} else if file_type.is_file() {
+				self.add(&path, LazyFile { path: entry.path(), file: None })?;
+			}
+		}
+		Ok(())
+	}
+
+	/// file flags describing the kinds of files in this archive, the games use
+	/// these to decide which archives to search
+	pub fn file_flags(&self) -> FileFlags {
+		self.folders
+			.values()
+			.flat_map(|folder| folder.files.values())
+			.map(|file| file_flags_for_name(&file.name))
+			.fold(FileFlags::empty(), |acc, flags| acc | flags)
+	}
+
+	fn archive_flags(&self) -> ArchiveFlags {
+		let mut flags = ArchiveFlags::empty();
+		if self.include_names {
+			flags |= ArchiveFlags::INCLUDE_DIR_NAMES | ArchiveFlags::INCLUDE_FILE_NAMES;
+		}
+		if self.compressed {
+			flags |= ArchiveFlags::COMPRESSED_ARCHIVE;
+		}
+		if self.embed_file_names && !matches!(self.version, ArchiveVersion::Oblivion) {
+			flags |= ArchiveFlags::EMBED_FILE_NAMES;
+		}
+		flags
+	}
+
+	/// write the archive to the start of `out`
+	pub fn write<W: Write + Seek>(self, out: &mut W) -> Result<()> {
+		let ver = self.version;
+		let folder_file_counts: Vec<usize> = self.folders.values().map(|f| f.files.len()).collect();
+		let file_count: usize = folder_file_counts.iter().sum();
+		let names = self.include_names;
+		let total_folder_name_length: usize = match names {
+			true => self.folders.values().map(|f| f.name.len() + 1).sum(),
+			false => 0,
+		};
+		let total_file_name_length: usize = match names {
+			true => self.folders.values().flat_map(|f| f.files.values()).map(|f| f.name.len() + 1).sum(),
+			false => 0,
+		};
+		let header = ArchiveHeader {
+			tag: *b"BSA\0",
+			version: ver,
+			offset: ArchiveHeader::SIZE as u32,
+			flags: self.archive_flags(),
+			folder_count: to_u32(self.folders.len())?,
+			file_count: to_u32(file_count)?,
+			total_folder_name_length: to_u32(total_folder_name_length)?,
+			total_file_name_length: to_u32(total_file_name_length)?,
+			file_flags: self.file_flags(),
+		};
+		out.seek(SeekFrom::Start(0))?;
+		header.write(out)?;
+
+		// each folder record points at its name and file records, offset by the
+		// size of the file name table, which is how the games store it
+		let mut block_offset = ArchiveHeader::SIZE + FolderRecord::size(ver) * self.folders.len();
+		for (&hash, folder) in &self.folders {
+			FolderRecord {
+				hash,
+				count: to_u32(folder.files.len())?,
+				offset: to_u32(block_offset + total_file_name_length)?,
+			}
+			.write(out, ver)?;
+			if names {
+				block_offset += folder.name.len() + 2;
+			}
+			block_offset += FileRecord::SIZE * folder.files.len();
+		}
+
+		// file records get filled in once we know where the data ended up
+		let mut record_offsets = Vec::with_capacity(self.folders.len());
+		for folder in self.folders.values() {
+			if names {
+				write_bzstring(out, &folder.name)?;
+			}
+			record_offsets.push(out.stream_position()?);
+			out.write_all(&vec![0; FileRecord::SIZE * folder.files.len()])?;
+		}
+		if names {
+			for file in self.folders.values().flat_map(|f| f.files.values()) {
+				out.write_all(&file.name)?;
+				out.write_u8(0)?;
+			}
+		}
+
+		let mut records = Vec::with_capacity(file_count);
+		for folder in self.folders.into_values() {
+			for (hash, mut file) in folder.files {
+				let offset = to_u32(out.stream_position()? as usize)?;
+				let mut data = Vec::new();
+				file.source.read_to_end(&mut data)?;
+				let mut size = 0;
+				if header.embeds_file_names() {
+					let path = virtual_path(&folder.name, &file.name);
+					out.write_u8(u8::try_from(path.len()).or(Err(Error::BogusSize))?)?;
+					out.write_all(&path)?;
+					size += path.len() + 1;
+				}
+				let mut toggle = 0;
+				if self.compressed {
+					let compressed = compress(ver, &data)?;
+					// small files can end up bigger compressed, store those as-is
+					if compressed.len() + 4 < data.len() {
+						out.write_u32::<LittleEndian>(to_u32(data.len())?)?;
+						out.write_all(&compressed)?;
+						size += compressed.len() + 4;
+					} else {
+						out.write_all(&data)?;
+						size += data.len();
+						toggle = FileRecord::COMPRESSION_TOGGLE;
+					}
+				} else {
+					out.write_all(&data)?;
+					size += data.len();
+				}
+				let size = to_u32(size)?;
+				if size & !FileRecord::SIZE_MASK != 0 {
+					return Err(Error::BogusSize);
+				}
+				records.push(FileRecord { hash, size: size | toggle, offset });
+			}
+		}
+
+		let mut records = records.into_iter();
+		for (&count, &offset) in folder_file_counts.iter().zip(&record_offsets) {
+			out.seek(SeekFrom::Start(offset))?;
+			for record in records.by_ref().take(count) {
+				record.write(out)?;
+			}
+		}
+		out.seek(SeekFrom::End(0))?;
+		Ok(())
+	}
+}
+
+fn to_u32(value: usize) -> Result<u32> {
+	u32::try_from(value).or(Err(Error::BogusSize))
+}
+
+fn write_bzstring(out: &mut impl Write, s: &[u8]) -> Result<()> {
+	out.write_u8(u8::try_from(s.len() + 1).or(Err(Error::BogusSize))?)?;
+	out.write_all(s)?;
+	out.write_u8(0)?;
+	Ok(())
+}
+
+fn file_flags_for_name(name: &[u8]) -> FileFlags {
+	let ext = match name.iter().rposition(|&c| c == b'.') {
+		Some(pos) => &name[pos + 1..],
+		None => &[][..],
+	};
+	match ext {
+		b"nif" | b"kf" | b"hkx" | b"tri" | b"btr" | b"bto" => FileFlags::MESHES,
+		b"dds" => FileFlags::TEXTURES,
+		b"xml" | b"swf" => FileFlags::MENUS,
+		b"wav" | b"xwm" => FileFlags::SOUNDS,
+		b"fuz" | b"lip" | b"mp3" | b"ogg" => FileFlags::VOICES,
+		b"fxp" | b"hlsl" => FileFlags::SHADERS,
+		b"spt" => FileFlags::TREES,
+		b"fnt" | b"tex" => FileFlags::FONTS,
+		_ => FileFlags::MISC,
+	}
+}
+
+impl ArchiveHeader {
+	pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+		out.write_all(&self.tag)?;
+		out.write_u32::<LittleEndian>(self.version as u32)?;
+		out.write_u32::<LittleEndian>(self.offset)?;
+		out.write_u32::<LittleEndian>(self.flags.bits())?;
+		out.write_u32::<LittleEndian>(self.folder_count)?;
+		out.write_u32::<LittleEndian>(self.file_count)?;
+		out.write_u32::<LittleEndian>(self.total_folder_name_length)?;
+		out.write_u32::<LittleEndian>(self.total_file_name_length)?;
+		out.write_u16::<LittleEndian>(self.file_flags.bits())?;
+		// padding
+		out.write_u16::<LittleEndian>(0)?;
+		Ok(())
+	}
+}
+
+impl FolderRecord {
+	pub fn write(&self, out: &mut impl Write, ver: ArchiveVersion) -> io::Result<()> {
+		out.write_u64::<LittleEndian>(self.hash)?;
+		out.write_u32::<LittleEndian>(self.count)?;
+		if ver.is_64_bit() {
+			out.write_u32::<LittleEndian>(0)?;
+		}
+		out.write_u32::<LittleEndian>(self.offset)?;
+		if ver.is_64_bit() {
+			out.write_u32::<LittleEndian>(0)?;
+		}
+		Ok(())
+	}
+}
+
+impl FileRecord {
+	pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+		out.write_u64::<LittleEndian>(self.hash)?;
+		out.write_u32::<LittleEndian>(self.size)?;
+		out.write_u32::<LittleEndian>(self.offset)?;
+		Ok(())
+	}
+}
+
+#[test]
+fn test_round_trip() {
+	use std::io::Cursor;
+	let content = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/test1_content");
+	let big = b"hello!".repeat(100);
+	for ver in [ArchiveVersion::Oblivion, ArchiveVersion::Skyrim, ArchiveVersion::SkyrimSE] {
+		for compressed in [false, true] {
+			let mut writer = BsaWriter::new(ver).compressed(compressed).embed_file_names(true);
+			writer.add_dir(&content).unwrap();
+			writer.add("meshes/Big.nif", &big[..]).unwrap();
+			assert!(matches!(writer.add("meshes\\big.nif", &b""[..]), Err(Error::DuplicateEntry)));
+			assert_eq!(writer.file_flags(), FileFlags::MESHES | FileFlags::MISC);
+			let mut out = Cursor::new(Vec::new());
+			writer.write(&mut out).unwrap();
+
+			let mut archive = IndexedArchive::new(out).unwrap();
+			assert_eq!(archive.verify(), []);
+			assert_eq!(archive.files().len(), 4);
+			for path in ["test.txt", "testfolder\\test.txt", "testfolder\\testnestedfolder\\test.txt"] {
+				let idx = archive.find(path).unwrap();
+				assert_eq!(&*archive.read(idx).unwrap().data, b"hello!");
+			}
+			let idx = archive.find("meshes\\big.nif").unwrap();
+			assert_eq!(archive.files()[idx].record.is_compressed(archive.header()), compressed);
+			let data = archive.read(idx).unwrap();
+			assert_eq!(*data.data, big[..]);
+			match ver {
+				ArchiveVersion::Oblivion => assert!(data.name.is_none()),
+				_ => assert_eq!(data.name.as_deref(), Some(&b"meshes\\big.nif"[..])),
+			}
+		}
+	}
+}
@@ -0,0 +1,358 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::collections::HashMap;
+
+use crate::common::*;
+use crate::espparser::{Plugin, PluginEntry, PluginRecord};
+use crate::formid::{FormKey, MasterResolver};
+use crate::records::*;
+use crate::schema::form_id_offsets;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirtyKind {
+    /// identical to master, an override that doesn't change anything
+    Itm,
+    /// undisabled deleted reference, deleting records from a master breaks
+    /// anything else referring to them
+    Udr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirtyRecord {
+    pub key: FormKey,
+    pub typ: [u8; 4],
+    pub kind: DirtyKind,
+}
+
+/// types of placed references, the only deleted records that can be fixed
+const REFERENCE_TYPES: [&[u8; 4]; 10] =
+    [b"REFR", b"ACHR", b"PGRE", b"PMIS", b"PHZD", b"PARW", b"PBAR", b"PBEA", b"PCON", b"PFLA"];
+
+const PLAYER_REF: u32 = 0x14;
+
+/// The records a plugin's masters define, the version a plugin overrides is
+/// the one from the last master in its master list to contain it
+pub struct Masters<'a> {
+    /// each record along with the index of its master's resolver
+    records: HashMap<FormKey, (&'a PluginRecord, usize)>,
+    resolvers: Vec<MasterResolver>,
+    remapped: Vec<String>,
+}
+
+impl<'a> Masters<'a> {
+    /// `masters` are the plugin's masters along with their names, any that
+    /// aren't given are treated as having no records
+    pub fn new(plugin: &Plugin, masters: &[(&str, &'a Plugin)]) -> Result<Self> {
+        let master_names: Vec<String> = plugin.plugin_header()?.master_names().map(str::to_owned).collect();
+        let mut ordered: Vec<_> = masters
+            .iter()
+            .filter_map(|&(name, master)| {
+                let idx = master_names.iter().position(|m| m.eq_ignore_ascii_case(name))?;
+                Some((idx, name, master))
+            })
+            .collect();
+        ordered.sort_by_key(|&(idx, _, _)| idx);
+        let mut records = HashMap::new();
+        let mut resolvers = Vec::new();
+        let mut remapped = Vec::new();
+        for (_, name, master) in ordered {
+            let header = master.plugin_header()?;
+            let resolver = MasterResolver::from_header(name, &header);
+            // raw form ids only line up if the master's masters are a prefix
+            // of ours and it's in the slot right after them
+            let same_indices = header
+                .master_names()
+                .chain([name])
+                .enumerate()
+                .all(|(idx, m)| master_names.get(idx).is_some_and(|ours| ours.eq_ignore_ascii_case(m)));
+            if !same_indices {
+                remapped.push(name.to_owned());
+            }
+            for record in master.records() {
+                records.insert(resolver.resolve(record.header.form_id), (record, resolvers.len()));
+            }
+            resolvers.push(resolver);
+        }
+        Ok(Self { records, resolvers, remapped })
+    }
+
+    /// The masters that number form ids differently from the plugin. Form ids
+    /// are only remapped in the fields [form_id_offsets] knows about, so ITMs
+    /// of their records with form ids in other fields can be missed
+    pub fn remapped(&self) -> &[String] {
+        &self.remapped
+    }
+
+    /// whether `record`, numbered by `resolver`, is identical to the master
+    /// record it overrides once form ids are normalised
+    fn is_itm(&self, key: &FormKey, record: &PluginRecord, resolver: &MasterResolver) -> Result<bool> {
+        let Some(&(master, idx)) = self.records.get(key) else {
+            return Ok(false);
+        };
+        let master_resolver = &self.resolvers[idx];
+        let same_id = |theirs: u32, ours: u32| {
+            // null refers to nothing in either plugin
+            (theirs == 0 && ours == 0) || (theirs != 0 && ours != 0 && master_resolver.resolve(theirs) == resolver.resolve(ours))
+        };
+        let ignored = COMPRESSED_FLAG;
+        if master.header.typ != record.header.typ
+            || master.header.flags & !ignored != record.header.flags & !ignored
+            || !same_id(master.header.form_id, record.header.form_id)
+        {
+            return Ok(false);
+        }
+        let theirs: Vec<_> = master.fields()?.collect::<Result<_>>()?;
+        let ours: Vec<_> = record.fields()?.collect::<Result<_>>()?;
+        if theirs.len() != ours.len() {
+            return Ok(false);
+        }
+        for (theirs, ours) in theirs.iter().zip(&ours) {
+            if theirs.typ != ours.typ || theirs.data.len() != ours.data.len() {
+                return Ok(false);
+            }
+            let mut start = 0;
+            for offset in form_id_offsets(&record.header.typ, ours) {
+                let id = |data: &[u8]| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+                if theirs.data[start..offset] != ours.data[start..offset] || !same_id(id(theirs.data), id(ours.data)) {
+                    return Ok(false);
+                }
+                start = offset + 4;
+            }
+            if theirs.data[start..] != ours.data[start..] {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// a deleted reference that overrides a master's, new records the plugin
+/// deletes itself don't break anything
+fn is_udr(key: &FormKey, name: &str, record: &PluginRecord) -> bool {
+    record.header.flags & DELETED_FLAG != 0
+        && REFERENCE_TYPES.contains(&&record.header.typ)
+        && !key.plugin.eq_ignore_ascii_case(name)
+}
+
+/// Find the ITMs and UDRs in `plugin`, named `name`.
+///
+/// Records are compared with the form ids in their header and in the fields
+/// [form_id_offsets] knows about normalised, so masters that number forms
+/// differently are handled. Form ids in other fields are compared raw, see
+/// [Masters::remapped].
+pub fn find_dirty(name: &str, plugin: &Plugin, masters: &Masters) -> Result<Vec<DirtyRecord>> {
+    let resolver = MasterResolver::from_header(name, &plugin.plugin_header()?);
+    let mut result = Vec::new();
+    for record in plugin.records() {
+        let key = resolver.resolve(record.header.form_id);
+        let kind = if is_udr(&key, name, record) {
+            DirtyKind::Udr
+        } else if !key.plugin.eq_ignore_ascii_case(name) && masters.is_itm(&key, record, &resolver)? {
+            DirtyKind::Itm
+        } else {
+            continue;
+        };
+        result.push(DirtyRecord { key, typ: record.header.typ, kind });
+    }
+    Ok(result)
+}
+
+/// Undelete a deleted reference the way the usual cleaning tools do, disable
+/// it, parent it to the player with the opposite enable state and sink it
+/// under the world.
+fn undelete(record: &mut PluginRecord) -> Result<()> {
+    let mut data = Vec::new();
+    for field in record.fields()? {
+        let field = field?;
+        match &field.typ {
+            b"XESP" => (),
+            b"DATA" if field.data.len() == 24 => {
+                let mut position = field.data.to_vec();
+                position[8..12].copy_from_slice(&(-30000.0f32).to_le_bytes());
+                write_field(&mut data, field.typ, &position);
+            }
+            _ => write_field(&mut data, field.typ, field.data),
+        }
+    }
+    let mut xesp = PLAYER_REF.to_le_bytes().to_vec();
+    xesp.extend_from_slice(&1u32.to_le_bytes()); // opposite of parent
+    write_field(&mut data, *b"XESP", &xesp);
+    record.set_data(data.into_boxed_slice())?;
+    record.header.flags = (record.header.flags & !DELETED_FLAG) | INITIALLY_DISABLED_FLAG;
+    Ok(())
+}
+
+// whether a record is followed by the group holding its children
+fn has_children(record: &PluginRecord, next: Option<&PluginEntry>) -> bool {
+    use GroupKind::*;
+    let Some(PluginEntry::Group(group)) = next else {
+        return false;
+    };
+    matches!(group.header.kind(), WorldChildren(id) | CellChildren(id) | TopicChildren(id) if id == record.header.form_id)
+}
+
+fn clean_entries(
+    entries: &mut Vec<PluginEntry>,
+    dirty: &HashMap<u32, DirtyKind>,
+    cleaned: &mut Vec<u32>,
+) -> Result<()> {
+    let mut idx = 0;
+    while idx < entries.len() {
+        let (current, rest) = entries[idx..].split_first_mut().unwrap();
+        match current {
+            PluginEntry::Record(record) => match dirty.get(&record.header.form_id) {
+                Some(DirtyKind::Udr) => {
+                    undelete(record)?;
+                    cleaned.push(record.header.form_id);
+                }
+                // records with children are left alone, removing them would
+                // orphan the children
+                Some(DirtyKind::Itm) if !has_children(record, rest.first()) => {
+                    cleaned.push(record.header.form_id);
+                    entries.remove(idx);
+                    continue;
+                }
+                _ => (),
+            },
+            PluginEntry::Group(group) => {
+                clean_entries(&mut group.children, dirty, cleaned)?;
+                if group.children.is_empty() {
+                    entries.remove(idx);
+                    continue;
+                }
+                group.header.group_size = group.size() as u32;
+            }
+        }
+        idx += 1;
+    }
+    Ok(())
+}
+
+/// Remove ITMs from `plugin` and undelete and disable its UDRs, returns the
+/// records that were fixed.
+///
+/// Groups left empty are removed, the record count in the header is updated
+/// when the plugin is written.
+pub fn clean(name: &str, plugin: &mut Plugin, masters: &Masters) -> Result<Vec<DirtyRecord>> {
+    let found = find_dirty(name, plugin, masters)?;
+    let resolver = MasterResolver::from_header(name, &plugin.plugin_header()?);
+    let dirty: HashMap<u32, DirtyKind> = found
+        .iter()
+        .map(|d| (resolver.unresolve(&d.key).unwrap(), d.kind))
+        .collect();
+    let mut cleaned = Vec::new();
+    let mut groups: Vec<PluginEntry> = plugin.groups.drain(..).map(PluginEntry::Group).collect();
+    clean_entries(&mut groups, &dirty, &mut cleaned)?;
+    plugin.groups = groups
+        .into_iter()
+        .map(|entry| match entry {
+            PluginEntry::Group(group) => group,
+            PluginEntry::Record(_) => unreachable!(),
+        })
+        .collect();
+    Ok(found
+        .into_iter()
+        .filter(|d| cleaned.contains(&resolver.unresolve(&d.key).unwrap()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::espparser::tests::{field, group, record, tes4};
+    use crate::header::PluginHeader;
+
+    fn refr(form_id: u32, flags: u32, z: f32) -> Vec<u8> {
+        let mut position = vec![0; 24];
+        position[8..12].copy_from_slice(&z.to_le_bytes());
+        let data = [field(b"NAME", &0x1234u32.to_le_bytes()), field(b"DATA", &position)].concat();
+        record(b"REFR", flags, form_id, &data)
+    }
+
+    fn cell(children: &[Vec<u8>]) -> Vec<u8> {
+        let id = 0x800u32;
+        group(*b"CELL", 0, &[group(0u32.to_le_bytes(), 2, &[group(0u32.to_le_bytes(), 3, &[
+            record(b"CELL", 0, id, &field(b"EDID", b"Cell\0")),
+            group(id.to_le_bytes(), 6, &[group(id.to_le_bytes(), 9, children)]),
+        ])])])
+    }
+
+    fn gmst(form_id: u32, value: u32) -> Vec<u8> {
+        record(b"GMST", 0, form_id, &[field(b"EDID", b"iSetting\0"), field(b"DATA", &value.to_le_bytes())].concat())
+    }
+
+    #[test]
+    fn itm_and_udr() {
+        let mut master = tes4(0.0, &[]);
+        master.extend(group(*b"GMST", 0, &[gmst(0x801, 1), gmst(0x802, 2)]));
+        master.extend(cell(&[refr(0x803, 0, 10.0), refr(0x804, 0, 10.0)]));
+        let master = Plugin::parse(&master[..]).unwrap();
+
+        let mut plugin = tes4(0.0, &["Master.esm"]);
+        plugin.extend(group(*b"GMST", 0, &[gmst(0x801, 1), gmst(0x802, 3), gmst(0x01000800, 4)]));
+        plugin.extend(cell(&[refr(0x803, DELETED_FLAG, 10.0), refr(0x804, 0, 10.0)]));
+        let mut plugin = Plugin::parse(&plugin[..]).unwrap();
+
+        let masters = Masters::new(&plugin, &[("Master.esm", &master)]).unwrap();
+        let dirty = find_dirty("Mod.esp", &plugin, &masters).unwrap();
+        let itm = |id| DirtyRecord { key: FormKey::new("Master.esm", id), typ: *b"GMST", kind: DirtyKind::Itm };
+        assert_eq!(dirty, [
+            itm(0x801),
+            DirtyRecord { key: FormKey::new("Master.esm", 0x800), typ: *b"CELL", kind: DirtyKind::Itm },
+            DirtyRecord { key: FormKey::new("Master.esm", 0x803), typ: *b"REFR", kind: DirtyKind::Udr },
+            DirtyRecord { key: FormKey::new("Master.esm", 0x804), typ: *b"REFR", kind: DirtyKind::Itm },
+        ]);
+
+        let cleaned = clean("Mod.esp", &mut plugin, &masters).unwrap();
+        // the cell has children so it stays
+        assert_eq!(cleaned.len(), 3);
+        let ids: Vec<_> = plugin.records().map(|r| r.header.form_id).collect();
+        assert_eq!(ids, [0x802, 0x01000800, 0x800, 0x803]);
+        let udr = plugin.records().last().unwrap();
+        assert_eq!(udr.header.flags, INITIALLY_DISABLED_FLAG);
+        let fields: Vec<_> = udr.fields().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(fields[1].data[8..12], (-30000.0f32).to_le_bytes());
+        assert_eq!(fields[2], FieldRef { typ: *b"XESP", data: &[0x14, 0, 0, 0, 1, 0, 0, 0] });
+
+        // the cleaned plugin is still valid
+        let mut written = Vec::new();
+        plugin.write(&mut written).unwrap();
+        let reparsed = Plugin::parse(&written[..]).unwrap();
+        assert_eq!(reparsed.groups, plugin.groups);
+        // 2 gmsts, the cell and the reference, plus 6 groups
+        assert_eq!(reparsed.plugin_header().unwrap().num_records, 10);
+        assert!(find_dirty("Mod.esp", &plugin, &masters).unwrap().iter().all(|d| d.typ == *b"CELL"));
+    }
+
+    #[test]
+    fn remapped_masters() {
+        // the master has a master of its own that the plugin lists second,
+        // so the records' own ids and the ids in them are numbered differently
+        let flst = |form_id: u32, entry: u32| record(b"FLST", 0, form_id, &field(b"LNAM", &entry.to_le_bytes()));
+        let mut master = tes4(0.0, &["Base.esm"]);
+        master.extend(group(*b"GMST", 0, &[gmst(0x01000801, 1)]));
+        master.extend(group(*b"FLST", 0, &[flst(0x01000802, 0x1234), flst(0x01000803, 0x1234)]));
+        master.extend(cell(&[refr(0x01000804, 0, 10.0)]));
+        let master = Plugin::parse(&master[..]).unwrap();
+        let mut plugin = tes4(0.0, &["Master.esm", "Base.esm"]);
+        plugin.extend(group(*b"GMST", 0, &[gmst(0x801, 1)]));
+        // the first list still holds Base.esm's 0x1234, the second now holds
+        // Master.esm's, even though its raw bytes haven't changed
+        plugin.extend(group(*b"FLST", 0, &[flst(0x802, 0x01001234), flst(0x803, 0x1234)]));
+        // NAME isn't a field the schema knows, so the same base object with
+        // a different raw id isn't recognised
+        let mut position = vec![0; 24];
+        position[8..12].copy_from_slice(&10.0f32.to_le_bytes());
+        let data = [field(b"NAME", &0x01001234u32.to_le_bytes()), field(b"DATA", &position)].concat();
+        // deleting its own new reference doesn't make the plugin dirty
+        plugin.extend(cell(&[record(b"REFR", 0, 0x804, &data), refr(0x02000900, DELETED_FLAG, 10.0)]));
+        let plugin = Plugin::parse(&plugin[..]).unwrap();
+        assert_eq!(PluginHeader::from_record(&plugin.header).unwrap().masters.len(), 2);
+        let masters = Masters::new(&plugin, &[("Master.esm", &master)]).unwrap();
+        assert_eq!(masters.remapped(), ["Master.esm"]);
+        let itm = |id, typ| DirtyRecord { key: FormKey::new("Master.esm", id), typ, kind: DirtyKind::Itm };
+        assert_eq!(find_dirty("Mod.esp", &plugin, &masters).unwrap(), [itm(0x801, *b"GMST"), itm(0x802, *b"FLST")]);
+    }
+}
@@ -34,6 +34,8 @@ pub enum Error {
     BogusSize,
     #[error("Parsing error")]
     ParseError,
+    #[error("Duplicate entry")]
+    DuplicateEntry,
     #[error("IO Error")]
     Io(#[from] io::Error),
 }
@@ -98,8 +100,7 @@ impl<R: Read> ReadExt for R {}
 
 impl<R: Read> ReadExtSkip for R {
     default fn skip_ext(&mut self, mut n: u64) -> io::Result<()> {
-        println!("Unbuffered");
-        let mut buf: [MaybeUninit<u8>; 255] = MaybeUninit::uninit_array();
+        let mut buf: [MaybeUninit<u8>; 255] = [const { MaybeUninit::uninit() }; 255];
         loop {
             let sz = min(255, n);
             if sz == 0 {
@@ -114,7 +115,6 @@ impl<R: Read> ReadExtSkip for R {
 
 impl<R: Read + SeekPredicate> ReadExtSkip for R {
     fn skip_ext(&mut self, n: u64) -> io::Result<()> {
-        println!("Buffered");
         self.seek(SeekFrom::Current(n as i64))?;
         Ok(())
     }
@@ -126,7 +126,7 @@ pub(crate) trait ParseCommon: Sized {
 
 impl<const N: usize> ParseCommon for [u8; N] {
     fn parse(input: &mut impl Read) -> Result<Self> {
-        let mut buf: [MaybeUninit<u8>; N] = MaybeUninit::uninit_array();
+        let mut buf: [MaybeUninit<u8>; N] = [const { MaybeUninit::uninit() }; N];
         let mut bbuf: BorrowedBuf = (&mut buf[..]).into();
         input.read_buf_exact(bbuf.unfilled())?;
         Ok(unsafe { MaybeUninit::array_assume_init(buf) })
@@ -0,0 +1,234 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::bsa::{path_hash, IndexedArchive};
+use crate::common::*;
+use crate::espparser::{PluginEvent, PluginReader};
+use crate::formid::{FormKey, MasterResolver};
+use crate::header::PluginHeader;
+
+/// A record that more than one plugin in a load order contains
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordConflict {
+    pub key: FormKey,
+    /// the last plugin in the load order to contain the record
+    pub winner: String,
+    /// every other plugin containing the record, in load order
+    pub losers: Vec<String>,
+}
+
+fn plugin_name(path: &Path) -> String {
+    path.file_name()
+        .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy())
+        .into_owned()
+}
+
+/// Every FormKey in one plugin, including the ones it overrides
+pub fn plugin_form_keys(name: &str, input: impl Read) -> Result<Vec<FormKey>> {
+    let mut reader = PluginReader::new(input);
+    let header = match reader.next_event()? {
+        Some(PluginEvent::Record(record)) => PluginHeader::from_record(&record)?,
+        _ => return Err(Error::InvalidTag),
+    };
+    let resolver = MasterResolver::from_header(name, &header);
+    let mut result = Vec::new();
+    for record in reader.records() {
+        result.push(resolver.resolve(record?.record.header.form_id));
+    }
+    Ok(result)
+}
+
+/// Records contained in more than one of `plugins`, which are given in load
+/// order, that is the last one wins.
+pub fn record_conflicts(plugins: &[impl AsRef<Path>]) -> Result<Vec<RecordConflict>> {
+    let mut inputs = Vec::new();
+    for path in plugins {
+        inputs.push((plugin_name(path.as_ref()), BufReader::new(File::open(path)?)));
+    }
+    record_conflicts_in(inputs)
+}
+
+/// Like [record_conflicts], for plugins that have already been opened
+pub fn record_conflicts_in<R: Read>(plugins: impl IntoIterator<Item = (String, R)>) -> Result<Vec<RecordConflict>> {
+    let mut seen: BTreeMap<FormKey, Vec<usize>> = BTreeMap::new();
+    let mut names = Vec::new();
+    for (idx, (name, input)) in plugins.into_iter().enumerate() {
+        names.push(name);
+        for key in plugin_form_keys(&names[idx], input)? {
+            let found_in = seen.entry(key).or_default();
+            // a plugin can't override itself, but don't count broken ones twice
+            if found_in.last() != Some(&idx) {
+                found_in.push(idx);
+            }
+        }
+    }
+    Ok(seen
+        .into_iter()
+        .filter(|(_, found_in)| found_in.len() > 1)
+        .map(|(key, mut found_in)| {
+            let winner = names[found_in.pop().unwrap()].clone();
+            let losers = found_in.into_iter().map(|idx| names[idx].clone()).collect();
+            RecordConflict { key, winner, losers }
+        })
+        .collect())
+}
+
+/// Somewhere a game looks for asset files
+#[derive(Debug, Clone)]
+pub enum AssetSource {
+    Archive(PathBuf),
+    /// a data directory with loose files
+    Loose(PathBuf),
+}
+
+impl AssetSource {
+    pub fn path(&self) -> &Path {
+        match self {
+            AssetSource::Archive(path) | AssetSource::Loose(path) => path,
+        }
+    }
+}
+
+/// An asset provided by more than one source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetConflict {
+    /// the virtual path, if any source included names
+    pub path: Option<String>,
+    pub folder_hash: u64,
+    pub file_hash: u64,
+    pub winner: PathBuf,
+    pub losers: Vec<PathBuf>,
+}
+
+fn loose_files(dir: &Path, prefix: &str, out: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_str().ok_or(Error::ParseError)?;
+        let path = if prefix.is_empty() {
+            file_name.to_owned()
+        } else {
+            format!("{prefix}\\{file_name}")
+        };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            loose_files(&entry.path(), &path, out)?;
+        } else if file_type.is_file() {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Assets provided by more than one of `sources`, which are given in
+/// priority order, that is the last one wins. Like plugins, files are matched
+/// by the hashes of their path, so archives without names work too.
+///
+/// The games let loose files win over every archive, so those should usually
+/// go last.
+pub fn asset_conflicts(sources: &[AssetSource]) -> Result<Vec<AssetConflict>> {
+    let mut seen: HashMap<(u64, u64), (Option<String>, Vec<usize>)> = HashMap::new();
+    for (idx, source) in sources.iter().enumerate() {
+        let mut add = |hashes, path: Option<String>| {
+            let (known_path, found_in) = seen.entry(hashes).or_default();
+            if known_path.is_none() {
+                *known_path = path;
+            }
+            if found_in.last() != Some(&idx) {
+                found_in.push(idx);
+            }
+        };
+        match source {
+            AssetSource::Archive(path) => {
+                let archive = IndexedArchive::open(path)?;
+                for file in archive.files() {
+                    let folder = &archive.folders()[file.folder_idx as usize];
+                    let path = file.path.as_ref().map(|p| p.to_string_lossy().into_owned());
+                    add((folder.hash, file.record.hash), path);
+                }
+            }
+            AssetSource::Loose(dir) => {
+                let mut files = Vec::new();
+                loose_files(dir, "", &mut files)?;
+                for path in files {
+                    let lower = path.to_lowercase();
+                    add(path_hash(lower.as_bytes()), Some(lower));
+                }
+            }
+        }
+    }
+    let mut result: Vec<_> = seen
+        .into_iter()
+        .filter(|(_, (_, found_in))| found_in.len() > 1)
+        .map(|((folder_hash, file_hash), (path, mut found_in))| {
+            let winner = sources[found_in.pop().unwrap()].path().to_owned();
+            let losers = found_in.into_iter().map(|idx| sources[idx].path().to_owned()).collect();
+            AssetConflict { path, folder_hash, file_hash, winner, losers }
+        })
+        .collect();
+    result.sort_by(|a, b| (&a.path, a.folder_hash, a.file_hash).cmp(&(&b.path, b.folder_hash, b.file_hash)));
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::espparser::tests::{field, group, record, tes4};
+
+    fn plugin(masters: &[&str], form_ids: &[u32]) -> Vec<u8> {
+        let mut result = tes4(0.0, masters);
+        let records: Vec<_> = form_ids.iter().map(|&id| record(b"GMST", 0, id, &field(b"EDID", b"x\0"))).collect();
+        result.extend(group(*b"GMST", 0, &records));
+        result
+    }
+
+    #[test]
+    fn records() {
+        let plugins = [
+            ("Base.esm", plugin(&[], &[0x800, 0x801, 0x802])),
+            ("A.esp", plugin(&["Base.esm"], &[0x800, 0x801, 0x01000900])),
+            ("B.esp", plugin(&["Base.esm", "A.esp"], &[0x801, 0x01000900])),
+        ];
+        let conflicts = record_conflicts_in(plugins.iter().map(|(name, data)| (name.to_string(), &data[..]))).unwrap();
+        assert_eq!(conflicts, [
+            RecordConflict {
+                key: FormKey::new("A.esp", 0x900),
+                winner: "B.esp".into(),
+                losers: vec!["A.esp".into()],
+            },
+            RecordConflict {
+                key: FormKey::new("Base.esm", 0x800),
+                winner: "A.esp".into(),
+                losers: vec!["Base.esm".into()],
+            },
+            RecordConflict {
+                key: FormKey::new("Base.esm", 0x801),
+                winner: "B.esp".into(),
+                losers: vec!["Base.esm".into(), "A.esp".into()],
+            },
+        ]);
+    }
+
+    #[test]
+    fn assets() {
+        let test1 = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/test1.bsa");
+        let content = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/test1_content");
+        let conflicts = asset_conflicts(&[AssetSource::Archive(test1.clone()), AssetSource::Loose(content.clone())]).unwrap();
+        let archive = IndexedArchive::open(&test1).unwrap();
+        assert_eq!(conflicts.len(), archive.files().len());
+        for conflict in &conflicts {
+            assert_eq!(conflict.winner, content);
+            assert_eq!(conflict.losers.len(), 1);
+            assert_eq!(conflict.losers[0], test1);
+        }
+        assert!(asset_conflicts(&[AssetSource::Archive(test1)]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::common::*;
+use crate::espparser::{Plugin, PluginRecord};
+use crate::formid::{FormKey, MasterResolver};
+use crate::records::{FieldRef, COMPRESSED_FLAG};
+use crate::schema::editor_id;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldChange {
+    Added { typ: [u8; 4], data: Vec<u8> },
+    Removed { typ: [u8; 4], data: Vec<u8> },
+    Changed { typ: [u8; 4], old: Vec<u8>, new: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordChange {
+    Added,
+    Removed,
+    Modified {
+        /// old and new record flags, if they changed
+        flags: Option<(u32, u32)>,
+        fields: Vec<FieldChange>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordDiff {
+    pub key: FormKey,
+    pub typ: [u8; 4],
+    /// from the new version of the record if there is one
+    pub editor_id: Option<String>,
+    pub change: RecordChange,
+}
+
+fn records_by_key<'a>(name: &str, plugin: &'a Plugin) -> Result<HashMap<FormKey, &'a PluginRecord>> {
+    let resolver = MasterResolver::from_header(name, &plugin.plugin_header()?);
+    Ok(plugin.records().map(|record| (resolver.resolve(record.header.form_id), record)).collect())
+}
+
+/// Fields are matched up by type and how many fields of that type came
+/// before them, so the second `LVLO` of the old record is compared with the
+/// second `LVLO` of the new one.
+pub fn diff_fields(old: &[FieldRef], new: &[FieldRef]) -> Vec<FieldChange> {
+    // a field type and how many of that type there have been so far
+    type Occurrence = ([u8; 4], usize);
+    fn occurrences<'a>(fields: &[FieldRef<'a>]) -> Vec<(Occurrence, &'a [u8])> {
+        let mut seen: HashMap<[u8; 4], usize> = HashMap::new();
+        fields
+            .iter()
+            .map(|field| {
+                let count = seen.entry(field.typ).or_default();
+                *count += 1;
+                ((field.typ, *count), field.data)
+            })
+            .collect()
+    }
+    let old = occurrences(old);
+    let new = occurrences(new);
+    let old_map: HashMap<_, _> = old.iter().copied().collect();
+    let new_map: HashMap<_, _> = new.iter().copied().collect();
+    let mut result = Vec::new();
+    for &(key, data) in &old {
+        match new_map.get(&key) {
+            None => result.push(FieldChange::Removed { typ: key.0, data: data.to_vec() }),
+            Some(&new_data) if new_data != data => {
+                result.push(FieldChange::Changed { typ: key.0, old: data.to_vec(), new: new_data.to_vec() })
+            }
+            Some(_) => (),
+        }
+    }
+    for &(key, data) in &new {
+        if !old_map.contains_key(&key) {
+            result.push(FieldChange::Added { typ: key.0, data: data.to_vec() });
+        }
+    }
+    result
+}
+
+/// Compare two versions of the plugin `name`, records are matched up by
+/// [FormKey] so changes to the master list don't show up as changes to
+/// every record.
+pub fn diff(name: &str, old: &Plugin, new: &Plugin) -> Result<Vec<RecordDiff>> {
+    let old_records = records_by_key(name, old)?;
+    let new_records = records_by_key(name, new)?;
+    let keys: BTreeSet<&FormKey> = old_records.keys().chain(new_records.keys()).collect();
+    let mut result = Vec::new();
+    for key in keys {
+        let (record, change) = match (old_records.get(key), new_records.get(key)) {
+            (Some(old), None) => (old, RecordChange::Removed),
+            (None, Some(new)) => (new, RecordChange::Added),
+            (Some(old), Some(new)) => {
+                let old_fields = old.fields()?.collect::<Result<Vec<_>>>()?;
+                let new_fields = new.fields()?.collect::<Result<Vec<_>>>()?;
+                let fields = diff_fields(&old_fields, &new_fields);
+                // compression doesn't change what's in the record
+                let flags_changed = (old.header.flags ^ new.header.flags) & !COMPRESSED_FLAG != 0;
+                let flags = flags_changed.then_some((old.header.flags, new.header.flags));
+                if fields.is_empty() && flags.is_none() {
+                    continue;
+                }
+                (new, RecordChange::Modified { flags, fields })
+            }
+            (None, None) => unreachable!(),
+        };
+        result.push(RecordDiff {
+            key: key.clone(),
+            typ: record.header.typ,
+            editor_id: editor_id(record)?,
+            change,
+        });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::espparser::tests::{field, group, record, tes4};
+
+    fn plugin(masters: &[&str], records: &[Vec<u8>]) -> Plugin {
+        let mut data = tes4(0.0, masters);
+        data.extend(group(*b"GMST", 0, records));
+        Plugin::parse(&data[..]).unwrap()
+    }
+
+    fn gmst(form_id: u32, edid: &str, values: &[u32]) -> Vec<u8> {
+        let mut data = field(b"EDID", format!("{edid}\0").as_bytes());
+        for value in values {
+            data.extend(field(b"DATA", &value.to_le_bytes()));
+        }
+        record(b"GMST", 0, form_id, &data)
+    }
+
+    #[test]
+    fn changes() {
+        let old = plugin(&["Skyrim.esm"], &[
+            gmst(0x00000100, "iSame", &[1]),
+            gmst(0x01000800, "iChanged", &[1, 2]),
+            gmst(0x01000801, "iRemoved", &[1]),
+        ]);
+        // a new master shifts every index, which shouldn't matter
+        let new = plugin(&["Skyrim.esm", "Update.esm"], &[
+            gmst(0x00000100, "iSame", &[1]),
+            gmst(0x02000800, "iChanged", &[1, 3, 4]),
+            gmst(0x01000900, "iAdded", &[1]),
+        ]);
+        let diffs = diff("Mod.esp", &old, &new).unwrap();
+        let summary: Vec<_> = diffs.iter().map(|d| (d.key.clone(), d.editor_id.clone().unwrap())).collect();
+        assert_eq!(summary, [
+            (FormKey::new("Mod.esp", 0x800), "iChanged".to_owned()),
+            (FormKey::new("Mod.esp", 0x801), "iRemoved".to_owned()),
+            (FormKey::new("Update.esm", 0x900), "iAdded".to_owned()),
+        ]);
+        assert_eq!(diffs[0].change, RecordChange::Modified {
+            flags: None,
+            fields: vec![
+                FieldChange::Changed { typ: *b"DATA", old: 2u32.to_le_bytes().to_vec(), new: 3u32.to_le_bytes().to_vec() },
+                FieldChange::Added { typ: *b"DATA", data: 4u32.to_le_bytes().to_vec() },
+            ],
+        });
+        assert_eq!(diffs[1].change, RecordChange::Removed);
+        assert_eq!(diffs[2].change, RecordChange::Added);
+    }
+}
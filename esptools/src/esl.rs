@@ -0,0 +1,139 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::ops::RangeInclusive;
+
+use crate::common::*;
+use crate::espparser::Plugin;
+use crate::formid::MasterResolver;
+use crate::header::{PluginHeader, LIGHT_FLAG};
+
+/// ids light plugins can use for their own records
+pub const LIGHT_RANGE: RangeInclusive<u32> = 0x800..=0xFFF;
+/// skyrim 1.6.1130 and later also allow ids below 0x800 in plugins with a
+/// header version of at least 1.71
+pub const EXTENDED_LIGHT_RANGE: RangeInclusive<u32> = 0x000..=0xFFF;
+
+pub fn light_range(header: &PluginHeader) -> RangeInclusive<u32> {
+    match header.version >= 1.71 {
+        true => EXTENDED_LIGHT_RANGE,
+        false => LIGHT_RANGE,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EslReport {
+    /// number of records the plugin itself defines
+    pub new_records: usize,
+    /// ids of new records that don't fit in a light plugin
+    pub out_of_range: Vec<u32>,
+    /// plugins that list this one as a master, they'd need their references
+    /// updated if the out of range ids were renumbered
+    pub dependents: Vec<String>,
+    pub range: RangeInclusive<u32>,
+}
+
+/// Why a plugin can't be flagged light as it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ineligible {
+    /// more new records than there are ids in the range, renumbering won't help
+    TooManyRecords,
+    /// some new records would need renumbering into the range
+    OutOfRange,
+}
+
+impl EslReport {
+    /// why the plugin can't be flagged light without changing any ids, if it can't
+    pub fn ineligible(&self) -> Option<Ineligible> {
+        if self.new_records > self.range.clone().count() {
+            Some(Ineligible::TooManyRecords)
+        } else if !self.out_of_range.is_empty() {
+            Some(Ineligible::OutOfRange)
+        } else {
+            None
+        }
+    }
+
+    /// whether the plugin can be flagged light without changing any ids
+    pub fn eligible(&self) -> bool {
+        self.ineligible().is_none()
+    }
+}
+
+/// Check whether `plugin`, named `name`, could be flagged as light.
+/// `others` are the rest of the load order, used to find dependents.
+pub fn check(name: &str, plugin: &Plugin, others: &[(&str, &PluginHeader)]) -> Result<EslReport> {
+    let header = plugin.plugin_header()?;
+    let resolver = MasterResolver::from_header(name, &header);
+    let range = light_range(&header);
+    let mut new_records = 0;
+    let mut out_of_range = Vec::new();
+    for record in plugin.records() {
+        let key = resolver.resolve(record.header.form_id);
+        if !key.plugin.eq_ignore_ascii_case(name) {
+            continue;
+        }
+        new_records += 1;
+        if !range.contains(&key.id) {
+            out_of_range.push(key.id);
+        }
+    }
+    let dependents = others
+        .iter()
+        .filter(|(_, other)| other.master_names().any(|m| m.eq_ignore_ascii_case(name)))
+        .map(|(other_name, _)| other_name.to_string())
+        .collect();
+    Ok(EslReport { new_records, out_of_range, dependents, range })
+}
+
+/// Set or clear the light flag in the plugin's header
+pub fn set_light(plugin: &mut Plugin, light: bool) {
+    match light {
+        true => plugin.header.header.flags |= LIGHT_FLAG,
+        false => plugin.header.header.flags &= !LIGHT_FLAG,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::espparser::tests::{field, group, record, tes4};
+
+    fn plugin(version: f32, form_ids: &[u32]) -> Plugin {
+        let mut data = tes4(version, &["Skyrim.esm"]);
+        let records: Vec<_> = form_ids.iter().map(|&id| record(b"KYWD", 0, id, &field(b"EDID", b"k\0"))).collect();
+        data.extend(group(*b"KYWD", 0, &records));
+        Plugin::parse(&data[..]).unwrap()
+    }
+
+    #[test]
+    fn eligibility() {
+        // overrides of the master don't count
+        let mut mod_esp = plugin(1.7, &[0x00012345, 0x01000800, 0x01000FFF]);
+        let report = check("Mod.esp", &mod_esp, &[]).unwrap();
+        assert_eq!(report.new_records, 2);
+        assert!(report.eligible());
+
+        let mut dependent = plugin(1.7, &[]).plugin_header().unwrap();
+        dependent.masters[0].name = "Mod.esp".into();
+        let too_low = plugin(1.7, &[0x01000100, 0x01001000]);
+        let report = check("Mod.esp", &too_low, &[("Patch.esp", &dependent)]).unwrap();
+        assert_eq!(report.out_of_range, [0x100, 0x1000]);
+        assert_eq!(report.dependents, ["Patch.esp"]);
+        assert_eq!(report.ineligible(), Some(Ineligible::OutOfRange));
+
+        // newer headers can use ids below 0x800, all the way down to 0
+        let report = check("Mod.esp", &plugin(1.71, &[0x01000000, 0x01000100]), &[]).unwrap();
+        assert!(report.eligible());
+        let full: Vec<u32> = (0x01000000..=0x01001000).collect();
+        let report = check("Mod.esp", &plugin(1.71, &full), &[]).unwrap();
+        assert_eq!(report.out_of_range, [0x1000]);
+        assert_eq!(report.ineligible(), Some(Ineligible::TooManyRecords));
+
+        set_light(&mut mod_esp, true);
+        let mut written = Vec::new();
+        mod_esp.write(&mut written).unwrap();
+        assert!(Plugin::parse(&written[..]).unwrap().plugin_header().unwrap().is_light());
+    }
+}
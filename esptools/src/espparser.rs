@@ -2,10 +2,17 @@
 //
 // SPDX-License-Identifier: LGPL-3.0-only
 
-use std::io::Read;
+use std::cell::OnceCell;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
 
 use crate::common::*;
+use crate::header::PluginHeader;
 use crate::records::*;
+use crate::GROUP_SIZE;
 
 pub trait ParseEsp: Sized {
     fn parse(input: &mut impl Read) -> Result<Self>;
@@ -26,9 +33,9 @@ impl ParseEsp for RecordHeader {
             form_id: u32::from_le_bytes(input.parse()?),
             timestamp: u16::from_le_bytes(input.parse()?),
             vcs_info: u16::from_le_bytes(input.parse()?),
-            internal_version: u16::from_le_bytes(input.parse()?)
+            internal_version: u16::from_le_bytes(input.parse()?),
+            unknown: u16::from_le_bytes(input.parse()?),
         };
-        input.skip(2)?;
         Ok(result)
     }
 }
@@ -41,9 +48,555 @@ impl ParseEsp for GroupHeader {
             label: input.parse()?,
             group_type: u32::from_le_bytes(input.parse()?),
             timestamp: u16::from_le_bytes(input.parse()?),
-            vcs_info: u16::from_le_bytes(input.parse()?)
+            vcs_info: u16::from_le_bytes(input.parse()?),
+            unknown: u32::from_le_bytes(input.parse()?),
         };
-        input.skip(4)?; // unknown padding
         Ok(result)
     }
 }
+
+/// A record along with its data as stored, still compressed if the record is
+#[derive(Debug, Clone)]
+pub struct PluginRecord {
+    pub header: RecordHeader,
+    pub data: Box<[u8]>,
+    // compressed records are inflated the first time their fields are needed
+    inflated: OnceCell<Box<[u8]>>,
+}
+
+impl PartialEq for PluginRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.header == other.header && self.data == other.data
+    }
+}
+
+impl Eq for PluginRecord {}
+
+impl PluginRecord {
+    pub fn new(header: RecordHeader, data: Box<[u8]>) -> Self {
+        Self { header, data, inflated: OnceCell::new() }
+    }
+
+    /// The field data, inflated if the record is compressed
+    pub fn data(&self) -> Result<&[u8]> {
+        if !self.header.is_compressed() {
+            return Ok(&self.data);
+        }
+        if let Some(inflated) = self.inflated.get() {
+            return Ok(inflated);
+        }
+        let inflated = decompress(&self.data)?;
+        Ok(self.inflated.get_or_init(|| inflated))
+    }
+
+    pub fn fields(&self) -> Result<Fields<'_>> {
+        Ok(fields(self.data()?))
+    }
+
+    /// Replace the record's field data, compressing it if the record is compressed
+    pub fn set_data(&mut self, data: Box<[u8]>) -> Result<()> {
+        self.data = match self.header.is_compressed() {
+            true => compress(&data)?,
+            false => data,
+        };
+        self.header.data_size = u32::try_from(self.data.len()).or(Err(Error::BogusSize))?;
+        self.inflated = OnceCell::new();
+        Ok(())
+    }
+
+    /// size of the record as written, including its header
+    pub fn size(&self) -> usize {
+        RecordHeader::SIZE + self.data.len()
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> Result<()> {
+        let header = &self.header;
+        out.write_all(&header.typ)?;
+        out.write_u32::<LittleEndian>(u32::try_from(self.data.len()).or(Err(Error::BogusSize))?)?;
+        out.write_u32::<LittleEndian>(header.flags)?;
+        out.write_u32::<LittleEndian>(header.form_id)?;
+        out.write_u16::<LittleEndian>(header.timestamp)?;
+        out.write_u16::<LittleEndian>(header.vcs_info)?;
+        out.write_u16::<LittleEndian>(header.internal_version)?;
+        out.write_u16::<LittleEndian>(header.unknown)?;
+        out.write_all(&self.data)?;
+        Ok(())
+    }
+}
+
+impl PluginGroup {
+    /// size of the group as written, including its header
+    pub fn size(&self) -> usize {
+        GROUP_SIZE + self.children.iter().map(PluginEntry::size).sum::<usize>()
+    }
+
+    /// write the group, its size is recomputed from the children
+    pub fn write(&self, out: &mut impl Write) -> Result<()> {
+        let header = &self.header;
+        out.write_all(b"GRUP")?;
+        out.write_u32::<LittleEndian>(u32::try_from(self.size()).or(Err(Error::BogusSize))?)?;
+        out.write_all(&header.label)?;
+        out.write_u32::<LittleEndian>(header.group_type)?;
+        out.write_u16::<LittleEndian>(header.timestamp)?;
+        out.write_u16::<LittleEndian>(header.vcs_info)?;
+        out.write_u32::<LittleEndian>(header.unknown)?;
+        for child in &self.children {
+            child.write(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl PluginEntry {
+    pub fn size(&self) -> usize {
+        match self {
+            PluginEntry::Record(record) => record.size(),
+            PluginEntry::Group(group) => group.size(),
+        }
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> Result<()> {
+        match self {
+            PluginEntry::Record(record) => record.write(out),
+            PluginEntry::Group(group) => group.write(out),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginGroup {
+    pub header: GroupHeader,
+    pub children: Vec<PluginEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginEntry {
+    Record(PluginRecord),
+    Group(PluginGroup),
+}
+
+/// A whole plugin file, the TES4 header record followed by the top level groups
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plugin {
+    pub header: PluginRecord,
+    pub groups: Vec<PluginGroup>,
+}
+
+#[derive(Debug)]
+pub enum PluginEvent {
+    Record(PluginRecord),
+    GroupStart(GroupHeader),
+    GroupEnd,
+}
+
+/// Streaming parser for plugin files, yields records as they're read and
+/// brackets the contents of each group with [PluginEvent::GroupStart] and
+/// [PluginEvent::GroupEnd].
+///
+/// Only the 24 byte record headers used by skyrim and later are supported.
+pub struct PluginReader<R: Read> {
+    input: R,
+    pos: u64,
+    // open groups and the offset they end at
+    groups: Vec<(GroupHeader, u64)>,
+}
+
+// like read_exact but a clean EOF before anything is read isn't an error
+fn read_header(input: &mut impl Read, buf: &mut [u8; GROUP_SIZE]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    match filled {
+        0 => Ok(false),
+        GROUP_SIZE => Ok(true),
+        _ => Err(Error::BogusSize),
+    }
+}
+
+impl<R: Read> PluginReader<R> {
+    pub fn new(input: R) -> Self {
+        Self { input, pos: 0, groups: Vec::new() }
+    }
+
+    /// headers of the groups enclosing the next event, outermost first
+    pub fn groups(&self) -> impl Iterator<Item = &GroupHeader> {
+        self.groups.iter().map(|(header, _)| header)
+    }
+
+    pub fn next_event(&mut self) -> Result<Option<PluginEvent>> {
+        if let Some(&(_, end)) = self.groups.last() {
+            if self.pos == end {
+                self.groups.pop();
+                return Ok(Some(PluginEvent::GroupEnd));
+            }
+        }
+        let start = self.pos;
+        let mut buf = [0u8; GROUP_SIZE];
+        if !read_header(&mut self.input, &mut buf)? {
+            return match self.groups.is_empty() {
+                true => Ok(None),
+                false => Err(Error::BogusSize),
+            };
+        }
+        self.pos += GROUP_SIZE as u64;
+        let limit = self.groups.last().map_or(u64::MAX, |&(_, end)| end);
+        if &buf[0..4] == b"GRUP" {
+            let header = GroupHeader::parse(&mut &buf[..])?;
+            let end = start + header.group_size as u64;
+            if (header.group_size as usize) < GROUP_SIZE || end > limit {
+                return Err(Error::BogusSize);
+            }
+            self.groups.push((header.clone(), end));
+            Ok(Some(PluginEvent::GroupStart(header)))
+        } else {
+            let header = RecordHeader::parse(&mut &buf[..])?;
+            if self.pos + header.data_size as u64 > limit {
+                return Err(Error::BogusSize);
+            }
+            let data = self.input.parse_bytes(header.data_size as usize)?;
+            self.pos += header.data_size as u64;
+            Ok(Some(PluginEvent::Record(PluginRecord::new(header, data))))
+        }
+    }
+
+    /// just the records, each with the headers of the groups it's in
+    pub fn records(self) -> Records<R> {
+        Records { reader: self }
+    }
+}
+
+impl<R: Read> Iterator for PluginReader<R> {
+    type Item = Result<PluginEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
+
+#[derive(Debug)]
+pub struct RecordInContext {
+    pub record: PluginRecord,
+    /// enclosing groups, outermost first
+    pub groups: Vec<GroupHeader>,
+}
+
+pub struct Records<R: Read> {
+    reader: PluginReader<R>,
+}
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = Result<RecordInContext>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.reader.next_event() {
+                Ok(Some(PluginEvent::Record(record))) => {
+                    let groups = self.reader.groups().cloned().collect();
+                    return Some(Ok(RecordInContext { record, groups }));
+                }
+                Ok(Some(_)) => continue,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl Plugin {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(BufReader::new(File::open(path)?))
+    }
+
+    pub fn parse(input: impl Read) -> Result<Self> {
+        let mut reader = PluginReader::new(input);
+        let header = match reader.next_event()? {
+            Some(PluginEvent::Record(header)) if &header.header.typ == b"TES4" => header,
+            _ => return Err(Error::InvalidTag),
+        };
+        let mut groups = Vec::new();
+        let mut open: Vec<PluginGroup> = Vec::new();
+        while let Some(event) = reader.next_event()? {
+            match event {
+                PluginEvent::GroupStart(header) => open.push(PluginGroup { header, children: Vec::new() }),
+                PluginEvent::GroupEnd => {
+                    let group = open.pop().unwrap();
+                    match open.last_mut() {
+                        Some(parent) => parent.children.push(PluginEntry::Group(group)),
+                        None => groups.push(group),
+                    }
+                }
+                PluginEvent::Record(record) => match open.last_mut() {
+                    Some(parent) => parent.children.push(PluginEntry::Record(record)),
+                    // only the header is allowed outside of a group
+                    None => return Err(Error::ParseError),
+                },
+            }
+        }
+        Ok(Self { header, groups })
+    }
+
+    /// the number of records and groups, not counting the header, as
+    /// stored in HEDR
+    pub fn record_count(&self) -> u32 {
+        fn count(entries: &[PluginEntry]) -> u32 {
+            entries
+                .iter()
+                .map(|entry| match entry {
+                    PluginEntry::Record(_) => 1,
+                    PluginEntry::Group(group) => 1 + count(&group.children),
+                })
+                .sum()
+        }
+        self.groups.iter().map(|group| 1 + count(&group.children)).sum()
+    }
+
+    /// Write the plugin back out, group sizes and the record count in the
+    /// header are updated to match the contents. Unmodified records are
+    /// written exactly as they were read.
+    pub fn write(&self, mut out: impl Write) -> Result<()> {
+        let count = self.record_count().to_le_bytes();
+        let hedr = self.header.fields()?.find(|field| field.as_ref().is_ok_and(|f| &f.typ == b"HEDR")).transpose()?;
+        match hedr {
+            Some(hedr) if hedr.data.len() == 12 && hedr.data[4..8] != count => {
+                let mut data = Vec::new();
+                for field in self.header.fields()? {
+                    let field = field?;
+                    match &field.typ {
+                        b"HEDR" => write_field(&mut data, field.typ, &[&field.data[..4], &count, &field.data[8..]].concat()),
+                        _ => write_field(&mut data, field.typ, field.data),
+                    }
+                }
+                let mut header = self.header.clone();
+                header.set_data(data.into_boxed_slice())?;
+                header.write(&mut out)?;
+            }
+            _ => self.header.write(&mut out)?,
+        }
+        for group in &self.groups {
+            group.write(&mut out)?;
+        }
+        Ok(())
+    }
+
+    pub fn plugin_header(&self) -> Result<PluginHeader> {
+        PluginHeader::from_record(&self.header)
+    }
+
+    /// every record except the header, depth first
+    pub fn records(&self) -> impl Iterator<Item = &PluginRecord> {
+        let mut stack: Vec<std::slice::Iter<'_, PluginEntry>> =
+            self.groups.iter().rev().map(|g| g.children.iter()).collect();
+        std::iter::from_fn(move || loop {
+            let entry = stack.last_mut()?.next();
+            match entry {
+                None => {
+                    stack.pop();
+                }
+                Some(PluginEntry::Record(record)) => return Some(record),
+                Some(PluginEntry::Group(group)) => stack.push(group.children.iter()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    pub(crate) fn field(typ: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut result = typ.to_vec();
+        result.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        result.extend_from_slice(data);
+        result
+    }
+
+    pub(crate) fn record(typ: &[u8; 4], flags: u32, form_id: u32, data: &[u8]) -> Vec<u8> {
+        let mut result = typ.to_vec();
+        result.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        result.extend_from_slice(&flags.to_le_bytes());
+        result.extend_from_slice(&form_id.to_le_bytes());
+        result.extend_from_slice(&[0; 4]); // timestamp and vcs info
+        result.extend_from_slice(&44u16.to_le_bytes());
+        result.extend_from_slice(&[0; 2]);
+        result.extend_from_slice(data);
+        result
+    }
+
+    pub(crate) fn group(label: [u8; 4], group_type: u32, children: &[Vec<u8>]) -> Vec<u8> {
+        let size: usize = GROUP_SIZE + children.iter().map(Vec::len).sum::<usize>();
+        let mut result = b"GRUP".to_vec();
+        result.extend_from_slice(&(size as u32).to_le_bytes());
+        result.extend_from_slice(&label);
+        result.extend_from_slice(&group_type.to_le_bytes());
+        result.extend_from_slice(&[0; 8]);
+        for child in children {
+            result.extend_from_slice(child);
+        }
+        result
+    }
+
+    /// a TES4 header record with the given HEDR version and masters
+    pub(crate) fn tes4(version: f32, masters: &[&str]) -> Vec<u8> {
+        let mut hedr = version.to_le_bytes().to_vec();
+        hedr.extend_from_slice(&[0; 8]);
+        let mut data = field(b"HEDR", &hedr);
+        for master in masters {
+            data.extend(field(b"MAST", format!("{master}\0").as_bytes()));
+            data.extend(field(b"DATA", &[0; 8]));
+        }
+        record(b"TES4", 0, 0, &data)
+    }
+
+    /// a small plugin with a top group of game settings and an interior cell
+    pub(crate) fn test_plugin() -> Vec<u8> {
+        let cell_id = 0x01000D62u32;
+        // 4 records and 6 groups
+        let hedr = [0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0];
+        let mut data = record(b"TES4", 0, 0, &field(b"HEDR", &hedr));
+        data.extend(group(*b"GMST", 0, &[
+            record(b"GMST", 0, 0x01000800, &[field(b"EDID", b"fTest\0"), field(b"DATA", &1.5f32.to_le_bytes())].concat()),
+            record(b"GMST", 0, 0x01000801, &field(b"EDID", b"iTest\0")),
+        ]));
+        data.extend(group(*b"CELL", 0, &[group(0u32.to_le_bytes(), 2, &[group(
+            2u32.to_le_bytes(),
+            3,
+            &[
+                record(b"CELL", 0, cell_id, &field(b"EDID", b"TestCell\0")),
+                group(cell_id.to_le_bytes(), 6, &[group(cell_id.to_le_bytes(), 9, &[record(
+                    b"REFR",
+                    0,
+                    0x01000802,
+                    &field(b"NAME", &0x14u32.to_le_bytes()),
+                )])]),
+            ],
+        )])]));
+        data
+    }
+
+    #[test]
+    fn empty_esm() {
+        let empty = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/empty.esm");
+        let plugin = Plugin::open(empty).unwrap();
+        assert_eq!(&plugin.header.header.typ, b"TES4");
+        let types: Vec<_> = plugin.header.fields().unwrap().map(|f| f.unwrap().typ).collect();
+        assert_eq!(types, [*b"HEDR", *b"CNAM", *b"INTV", *b"INCC"]);
+        assert!(plugin.groups.is_empty());
+    }
+
+    #[test]
+    fn nested_groups() {
+        let plugin = Plugin::parse(&test_plugin()[..]).unwrap();
+        assert_eq!(plugin.groups.len(), 2);
+        assert_eq!(plugin.groups[0].header.kind(), GroupKind::Top(*b"GMST"));
+        let ids: Vec<_> = plugin.records().map(|r| r.header.form_id).collect();
+        assert_eq!(ids, [0x01000800, 0x01000801, 0x01000D62, 0x01000802]);
+
+        let records: Vec<_> = PluginReader::new(&test_plugin()[..])
+            .records()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(records.len(), 5);
+        assert!(records[0].groups.is_empty());
+        let refr = &records[4];
+        assert_eq!(&refr.record.header.typ, b"REFR");
+        let kinds: Vec<_> = refr.groups.iter().map(GroupHeader::kind).collect();
+        assert_eq!(kinds, [
+            GroupKind::Top(*b"CELL"),
+            GroupKind::InteriorCellBlock(0),
+            GroupKind::InteriorCellSubBlock(2),
+            GroupKind::CellChildren(0x01000D62),
+            GroupKind::CellTemporaryChildren(0x01000D62),
+        ]);
+    }
+
+    #[test]
+    fn compressed_record() {
+        use std::io::Write;
+
+        use flate2::{write::ZlibEncoder, Compression};
+
+        let fields_data = [field(b"EDID", b"NavMesh\0"), field(b"NVNM", &[3; 200])].concat();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&fields_data).unwrap();
+        let mut data = (fields_data.len() as u32).to_le_bytes().to_vec();
+        data.extend(encoder.finish().unwrap());
+
+        let mut plugin = record(b"TES4", 0, 0, &field(b"HEDR", &[0; 12]));
+        plugin.extend(group(*b"NAVM", 0, &[record(b"NAVM", COMPRESSED_FLAG, 0x01000900, &data)]));
+        let plugin = Plugin::parse(&plugin[..]).unwrap();
+        let navm = plugin.records().next().unwrap();
+        assert!(navm.header.is_compressed());
+        assert_eq!(navm.data().unwrap(), &fields_data[..]);
+        let result: Vec<_> = navm.fields().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0], FieldRef { typ: *b"EDID", data: b"NavMesh\0" });
+        assert_eq!(result[1].data, &[3; 200]);
+
+        // compressed records are written back untouched, or recompressed if changed
+        let mut written = Vec::new();
+        plugin.write(&mut written).unwrap();
+        assert_eq!(Plugin::parse(&written[..]).unwrap().groups, plugin.groups);
+        let mut plugin = plugin;
+        let PluginEntry::Record(navm) = &mut plugin.groups[0].children[0] else { unreachable!() };
+        navm.set_data(field(b"EDID", b"Changed\0").into_boxed_slice()).unwrap();
+        assert!(navm.header.is_compressed());
+        assert_eq!(navm.fields().unwrap().next().unwrap().unwrap().data, b"Changed\0");
+        assert_eq!(navm.data.len(), navm.header.data_size as usize);
+
+        // a size that doesn't match what's actually there
+        let mut bogus = data.clone();
+        bogus[0] += 1;
+        assert!(decompress(&bogus).is_err());
+        bogus[0] -= 2;
+        assert!(decompress(&bogus).is_err());
+        // a huge one fails without allocating it up front
+        bogus[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decompress(&bogus).is_err());
+    }
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let mut written = Vec::new();
+        Plugin::parse(data).unwrap().write(&mut written).unwrap();
+        written
+    }
+
+    #[test]
+    fn write() {
+        let empty = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/empty.esm")).unwrap();
+        assert_eq!(round_trip(&empty), empty);
+        let mut data = test_plugin();
+        // the unknown fields at the end of record and group headers are kept
+        data[22] = 0xAB;
+        let grup = data.windows(4).position(|w| w == b"GRUP").unwrap();
+        data[grup + 20] = 0xCD;
+        assert_eq!(round_trip(&data), data);
+
+        // record counts and group sizes follow the contents
+        let mut plugin = Plugin::parse(&data[..]).unwrap();
+        plugin.groups[0].children.pop();
+        let mut written = Vec::new();
+        plugin.write(&mut written).unwrap();
+        let written = Plugin::parse(&written[..]).unwrap();
+        assert_eq!(written.plugin_header().unwrap().num_records, 9);
+        assert_eq!(written.groups[0].header.group_size as usize, plugin.groups[0].size());
+    }
+
+    #[test]
+    fn truncated() {
+        let data = test_plugin();
+        assert!(Plugin::parse(&data[..data.len() - 1]).is_err());
+        // a group claiming to be bigger than its parent
+        let mut data = test_plugin();
+        let inner = data.windows(4).rposition(|w| w == b"GRUP").unwrap();
+        data[inner + 4] += 1;
+        assert!(Plugin::parse(&data[..]).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use crate::header::PluginHeader;
+
+/// A record identity that doesn't depend on load order, the plugin that
+/// first defined the record and its id within that plugin.
+///
+/// Plugin names compare case-insensitively, like the game does, but keep
+/// the casing they were created with for display.
+#[derive(Debug, Clone)]
+pub struct FormKey {
+    pub plugin: String,
+    pub id: u32,
+}
+
+impl FormKey {
+    pub fn new(plugin: impl Into<String>, id: u32) -> Self {
+        Self { plugin: plugin.into(), id }
+    }
+}
+
+impl FormKey {
+    fn folded_plugin(&self) -> impl Iterator<Item = u8> + '_ {
+        self.plugin.bytes().map(|b| b.to_ascii_lowercase())
+    }
+}
+
+impl PartialEq for FormKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id && same_plugin(&self.plugin, &other.plugin)
+    }
+}
+
+impl Eq for FormKey {}
+
+impl Hash for FormKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for b in self.folded_plugin() {
+            state.write_u8(b);
+        }
+        // same terminator str uses, so names can't run into the id
+        state.write_u8(0xff);
+        self.id.hash(state);
+    }
+}
+
+impl PartialOrd for FormKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FormKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.folded_plugin().cmp(other.folded_plugin()).then(self.id.cmp(&other.id))
+    }
+}
+
+impl fmt::Display for FormKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:06X}:{}", self.id, self.plugin)
+    }
+}
+
+/// How a plugin's records are numbered once it's loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginKind {
+    /// one of 253 full slots, ids are 0xXXYYYYYY
+    Full,
+    /// starfield's medium plugins, ids are 0xFDXXYYYY
+    Medium,
+    /// esl flagged plugins, ids are 0xFEXXXYYY
+    Light,
+}
+
+impl PluginKind {
+    pub fn from_header(header: &PluginHeader) -> Self {
+        if header.is_light() {
+            Self::Light
+        } else if header.is_medium() {
+            Self::Medium
+        } else {
+            Self::Full
+        }
+    }
+
+    /// mask for the part of a loaded form id that's local to the plugin
+    pub fn id_mask(self) -> u32 {
+        match self {
+            Self::Full => 0x00FFFFFF,
+            Self::Medium => 0x0000FFFF,
+            Self::Light => 0x00000FFF,
+        }
+    }
+}
+
+/// Maps the raw form ids in one plugin to [FormKey]s and back.
+///
+/// The top byte of a raw id indexes the plugin's master list, with any
+/// index past the end meaning the plugin itself.
+#[derive(Debug, Clone)]
+pub struct MasterResolver {
+    plugin: String,
+    masters: Vec<String>,
+}
+
+fn same_plugin(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+impl MasterResolver {
+    pub fn new(plugin: impl Into<String>, masters: Vec<String>) -> Self {
+        Self { plugin: plugin.into(), masters }
+    }
+
+    pub fn from_header(plugin: impl Into<String>, header: &PluginHeader) -> Self {
+        Self::new(plugin, header.master_names().map(str::to_owned).collect())
+    }
+
+    pub fn resolve(&self, raw: u32) -> FormKey {
+        let plugin = self.masters.get((raw >> 24) as usize).unwrap_or(&self.plugin);
+        FormKey::new(plugin.clone(), raw & 0x00FFFFFF)
+    }
+
+    /// The raw id this plugin would use to refer to `key`, if `key` is
+    /// from the plugin itself or one of its masters
+    pub fn unresolve(&self, key: &FormKey) -> Option<u32> {
+        if key.id > 0x00FFFFFF {
+            return None;
+        }
+        let idx = match self.masters.iter().position(|m| same_plugin(m, &key.plugin)) {
+            Some(idx) => idx,
+            None if same_plugin(&self.plugin, &key.plugin) => self.masters.len(),
+            None => return None,
+        };
+        Some(((idx as u32) << 24) | key.id)
+    }
+}
+
+/// Maps [FormKey]s to the form ids records get at runtime and back, for
+/// a given load order.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOrder {
+    plugins: Vec<(String, PluginKind, u32)>,
+    full: u32,
+    medium: u32,
+    light: u32,
+}
+
+impl LoadOrder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a plugin, returns the prefix its ids get, or None if there's
+    /// no slot left for it
+    pub fn push(&mut self, plugin: impl Into<String>, kind: PluginKind) -> Option<u32> {
+        let prefix = match kind {
+            PluginKind::Full if self.full < 0xFD => {
+                self.full += 1;
+                (self.full - 1) << 24
+            }
+            PluginKind::Medium if self.medium < 0x100 => {
+                self.medium += 1;
+                0xFD000000 | ((self.medium - 1) << 16)
+            }
+            PluginKind::Light if self.light < 0x1000 => {
+                self.light += 1;
+                0xFE000000 | ((self.light - 1) << 12)
+            }
+            _ => return None,
+        };
+        self.plugins.push((plugin.into(), kind, prefix));
+        Some(prefix)
+    }
+
+    pub fn plugins(&self) -> impl Iterator<Item = (&str, PluginKind)> {
+        self.plugins.iter().map(|(name, kind, _)| (name.as_str(), *kind))
+    }
+
+    pub fn position(&self, plugin: &str) -> Option<usize> {
+        self.plugins.iter().position(|(name, _, _)| same_plugin(name, plugin))
+    }
+
+    pub fn resolve(&self, key: &FormKey) -> Option<u32> {
+        let (_, kind, prefix) = &self.plugins[self.position(&key.plugin)?];
+        (key.id & !kind.id_mask() == 0).then_some(prefix | key.id)
+    }
+
+    pub fn lookup(&self, id: u32) -> Option<FormKey> {
+        let (name, kind, _) = self
+            .plugins
+            .iter()
+            .find(|(_, kind, prefix)| id & !kind.id_mask() == *prefix)?;
+        Some(FormKey::new(name.clone(), id & kind.id_mask()))
+    }
+}
+
+impl<S: Into<String>> FromIterator<(S, PluginKind)> for LoadOrder {
+    fn from_iter<T: IntoIterator<Item = (S, PluginKind)>>(iter: T) -> Self {
+        let mut result = Self::new();
+        for (plugin, kind) in iter {
+            result.push(plugin, kind);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::espparser::tests::{field, record};
+    use crate::header::{LIGHT_FLAG, MASTER_FLAG, MEDIUM_FLAG};
+
+    #[test]
+    fn key_case() {
+        let key = FormKey::new("Skyrim.esm", 0x012E46);
+        let lower = FormKey::new("skyrim.ESM", 0x012E46);
+        assert_eq!(key, lower);
+        assert_eq!(key.cmp(&lower), Ordering::Equal);
+        assert_ne!(key, FormKey::new("Skyrim.esm", 0x012E47));
+        assert!(FormKey::new("a.esp", 0x900) < FormKey::new("B.esp", 0x800));
+        assert_eq!(HashSet::from([key.clone(), lower]).len(), 1);
+        assert_eq!(key.to_string(), "012E46:Skyrim.esm");
+    }
+
+    #[test]
+    fn kind_from_header() {
+        let mut hedr = 0.96f32.to_le_bytes().to_vec();
+        hedr.extend(0u32.to_le_bytes());
+        hedr.extend(0x800u32.to_le_bytes());
+        let kind = |flags| {
+            let plugin = record(b"TES4", flags, 0, &field(b"HEDR", &hedr));
+            PluginKind::from_header(&PluginHeader::read(&plugin[..]).unwrap())
+        };
+        assert_eq!(kind(MASTER_FLAG), PluginKind::Full);
+        assert_eq!(kind(MASTER_FLAG | MEDIUM_FLAG), PluginKind::Medium);
+        assert_eq!(kind(MASTER_FLAG | LIGHT_FLAG), PluginKind::Light);
+    }
+
+    #[test]
+    fn masters() {
+        let resolver = MasterResolver::new("Mod.esp", vec!["Skyrim.esm".into(), "Update.esm".into()]);
+        assert_eq!(resolver.resolve(0x00012E46), FormKey::new("Skyrim.esm", 0x012E46));
+        assert_eq!(resolver.resolve(0x01000800), FormKey::new("Update.esm", 0x800));
+        assert_eq!(resolver.resolve(0x02000801), FormKey::new("Mod.esp", 0x801));
+        assert_eq!(resolver.unresolve(&FormKey::new("update.esm", 0x800)), Some(0x01000800));
+        assert_eq!(resolver.unresolve(&FormKey::new("Mod.esp", 0x801)), Some(0x02000801));
+        assert_eq!(resolver.unresolve(&FormKey::new("Other.esp", 0x801)), None);
+    }
+
+    #[test]
+    fn load_order() {
+        let order: LoadOrder = [
+            ("Skyrim.esm", PluginKind::Full),
+            ("Light.esp", PluginKind::Light),
+            ("Mod.esp", PluginKind::Full),
+            ("Medium.esm", PluginKind::Medium),
+            ("Light2.esl", PluginKind::Light),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(order.resolve(&FormKey::new("Skyrim.esm", 0x012E46)), Some(0x00012E46));
+        assert_eq!(order.resolve(&FormKey::new("Mod.esp", 0x801)), Some(0x01000801));
+        assert_eq!(order.resolve(&FormKey::new("Light2.esl", 0x801)), Some(0xFE001801));
+        assert_eq!(order.resolve(&FormKey::new("Medium.esm", 0x801)), Some(0xFD000801));
+        // too big for a light plugin
+        assert_eq!(order.resolve(&FormKey::new("Light.esp", 0x1000)), None);
+        assert_eq!(order.resolve(&FormKey::new("Missing.esp", 0x801)), None);
+        for id in [0x00012E46, 0x01000801, 0xFE000801, 0xFE001801, 0xFD000801] {
+            let key = order.lookup(id).unwrap();
+            assert_eq!(order.resolve(&key), Some(id));
+        }
+        assert_eq!(order.lookup(0xFE002801), None);
+    }
+}
@@ -0,0 +1,182 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::common::*;
+use crate::espparser::{PluginEvent, PluginReader, PluginRecord};
+use crate::records::{le_u32, zstring};
+
+pub const MASTER_FLAG: u32 = 0x1;
+pub const LOCALIZED_FLAG: u32 = 0x80;
+pub const LIGHT_FLAG: u32 = 0x200;
+/// starfield only
+pub const MEDIUM_FLAG: u32 = 0x400;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Master {
+    pub name: String,
+    /// the DATA field after each MAST, supposed to be the master's size but
+    /// nearly always zero
+    pub data: u64,
+}
+
+/// The decoded TES4 record at the start of every plugin
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginHeader {
+    pub flags: u32,
+    pub version: f32,
+    pub num_records: u32,
+    pub next_object_id: u32,
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub masters: Vec<Master>,
+    /// form ids of overridden records, only in masters
+    pub overrides: Vec<u32>,
+    pub internal_version: Option<u32>,
+    pub incc: Option<u32>,
+}
+
+impl PluginHeader {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Read just the header from the start of a plugin, without touching the rest
+    pub fn read(input: impl Read) -> Result<Self> {
+        match PluginReader::new(input).next_event()? {
+            Some(PluginEvent::Record(record)) => Self::from_record(&record),
+            _ => Err(Error::InvalidTag),
+        }
+    }
+
+    pub fn from_record(record: &PluginRecord) -> Result<Self> {
+        if &record.header.typ != b"TES4" {
+            return Err(Error::InvalidTag);
+        }
+        let mut result = Self {
+            flags: record.header.flags,
+            version: 0.0,
+            num_records: 0,
+            next_object_id: 0,
+            author: None,
+            description: None,
+            masters: Vec::new(),
+            overrides: Vec::new(),
+            internal_version: None,
+            incc: None,
+        };
+        let mut seen_hedr = false;
+        for field in record.fields()? {
+            let field = field?;
+            match &field.typ {
+                b"HEDR" => {
+                    let hedr: &[u8; 12] = field.data.try_into().map_err(|_| Error::BogusSize)?;
+                    result.version = f32::from_le_bytes(hedr[0..4].try_into().unwrap());
+                    result.num_records = le_u32(&hedr[4..8])?;
+                    result.next_object_id = le_u32(&hedr[8..12])?;
+                    seen_hedr = true;
+                }
+                b"CNAM" => result.author = Some(zstring(field.data)),
+                b"SNAM" => result.description = Some(zstring(field.data)),
+                b"MAST" => result.masters.push(Master { name: zstring(field.data), data: 0 }),
+                b"DATA" => {
+                    let master = result.masters.last_mut().ok_or(Error::ParseError)?;
+                    master.data = u64::from_le_bytes(field.data.try_into().map_err(|_| Error::BogusSize)?);
+                }
+                b"ONAM" => {
+                    if !field.data.len().is_multiple_of(4) {
+                        return Err(Error::BogusSize);
+                    }
+                    result.overrides = field.data.chunks_exact(4).map(|id| le_u32(id).unwrap()).collect();
+                }
+                b"INTV" => result.internal_version = Some(le_u32(field.data)?),
+                b"INCC" => result.incc = Some(le_u32(field.data)?),
+                _ => (),
+            }
+        }
+        if !seen_hedr {
+            return Err(Error::ParseError);
+        }
+        Ok(result)
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.flags & MASTER_FLAG != 0
+    }
+
+    pub fn is_light(&self) -> bool {
+        self.flags & LIGHT_FLAG != 0
+    }
+
+    pub fn is_medium(&self) -> bool {
+        self.flags & MEDIUM_FLAG != 0
+    }
+
+    /// whether strings are stored in separate string tables
+    pub fn is_localized(&self) -> bool {
+        self.flags & LOCALIZED_FLAG != 0
+    }
+
+    pub fn master_names(&self) -> impl Iterator<Item = &str> {
+        self.masters.iter().map(|m| m.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::espparser::tests::{field, record};
+
+    #[test]
+    fn empty_esm() {
+        let empty = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/empty.esm");
+        let header = PluginHeader::open(empty).unwrap();
+        assert_eq!(header.version, 1.7);
+        assert_eq!(header.num_records, 0);
+        assert_eq!(header.next_object_id, 0x800);
+        assert_eq!(header.author.as_deref(), Some("DEFAULT"));
+        assert_eq!(header.description, None);
+        assert!(header.masters.is_empty());
+        assert_eq!(header.internal_version, Some(1));
+        assert_eq!(header.incc, Some(0));
+        assert!(header.is_master());
+        assert!(!header.is_light());
+        assert!(!header.is_medium());
+        assert!(!header.is_localized());
+    }
+
+    #[test]
+    fn masters() {
+        let mut hedr = 1.71f32.to_le_bytes().to_vec();
+        hedr.extend(3u32.to_le_bytes());
+        hedr.extend(0x900u32.to_le_bytes());
+        let data = [
+            field(b"HEDR", &hedr),
+            field(b"CNAM", b"someone\0"),
+            field(b"SNAM", b"a description\0"),
+            field(b"MAST", b"Skyrim.esm\0"),
+            field(b"DATA", &[0; 8]),
+            field(b"MAST", b"Update.esm\0"),
+            field(b"DATA", &[0; 8]),
+            field(b"ONAM", &[0x14, 0, 0, 1, 0x15, 0, 0, 1]),
+        ]
+        .concat();
+        let plugin = record(b"TES4", MASTER_FLAG | LIGHT_FLAG | LOCALIZED_FLAG, 0, &data);
+        let header = PluginHeader::read(&plugin[..]).unwrap();
+        assert_eq!(header.num_records, 3);
+        assert_eq!(header.description.as_deref(), Some("a description"));
+        assert_eq!(header.master_names().collect::<Vec<_>>(), ["Skyrim.esm", "Update.esm"]);
+        assert_eq!(header.overrides, [0x01000014, 0x01000015]);
+        assert!(header.is_master() && header.is_light() && header.is_localized());
+        assert!(!header.is_medium());
+
+        let not_tes4 = record(b"GMST", 0, 0, &data);
+        assert!(PluginHeader::read(&not_tes4[..]).is_err());
+    }
+}
@@ -3,15 +3,26 @@
 // SPDX-License-Identifier: LGPL-3.0-only
 
 #![feature(read_buf)]
-#![feature(maybe_uninit_uninit_array)]
+#![feature(core_io_borrowed_buf)]
 #![feature(maybe_uninit_array_assume_init)]
-#![feature(new_uninit)]
 #![feature(min_specialization)]
 #![feature(rustc_attrs)]
 
 pub mod records;
 pub mod bsa;
+pub mod ba2;
 pub mod espparser;
+pub mod header;
+pub mod formid;
+pub mod conflicts;
+pub mod cleaning;
+pub mod schema;
+pub mod strings;
+pub mod esl;
+pub mod loadorder;
+pub mod diff;
 mod common;
 
+pub use common::{Error, Result};
+
 pub const GROUP_SIZE: usize = 24;
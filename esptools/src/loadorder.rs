@@ -0,0 +1,202 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use thiserror::Error;
+
+use crate::header::PluginHeader;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingMaster {
+    pub plugin: String,
+    pub master: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum SortError {
+    #[error("missing masters: {0:?}")]
+    MissingMasters(Vec<MissingMaster>),
+    /// plugins that each have to load after the next, and the last after the first
+    #[error("cyclic dependency between {0:?}")]
+    Cycle(Vec<String>),
+}
+
+/// A user supplied rule that `plugin` loads after `after`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadAfter {
+    pub plugin: String,
+    pub after: String,
+}
+
+fn is_master_file(name: &str, header: &PluginHeader) -> bool {
+    let ext = name.rsplit_once('.').map_or("", |(_, ext)| ext);
+    header.is_master() || ext.eq_ignore_ascii_case("esm") || ext.eq_ignore_ascii_case("esl")
+}
+
+/// Sort `plugins` so every plugin loads after its masters and anything `rules`
+/// says it should load after, with masters before other plugins where
+/// dependencies allow it. Otherwise the order `plugins` are given in is kept.
+///
+/// Rules naming plugins that aren't in `plugins` are ignored.
+pub fn sort(plugins: &[(&str, &PluginHeader)], rules: &[LoadAfter]) -> Result<Vec<String>, SortError> {
+    let index: HashMap<String, usize> =
+        plugins.iter().enumerate().map(|(idx, (name, _))| (name.to_lowercase(), idx)).collect();
+    let lookup = |name: &str| index.get(&name.to_lowercase()).copied();
+
+    // edges from each plugin to the ones that have to load after it
+    let mut after: Vec<Vec<usize>> = vec![Vec::new(); plugins.len()];
+    let mut missing = Vec::new();
+    for (idx, (name, header)) in plugins.iter().enumerate() {
+        for master in header.master_names() {
+            match lookup(master) {
+                Some(master) => after[master].push(idx),
+                None => missing.push(MissingMaster { plugin: name.to_string(), master: master.to_owned() }),
+            }
+        }
+    }
+    if !missing.is_empty() {
+        return Err(SortError::MissingMasters(missing));
+    }
+    for rule in rules {
+        if let (Some(plugin), Some(before)) = (lookup(&rule.plugin), lookup(&rule.after)) {
+            after[before].push(plugin);
+        }
+    }
+
+    let mut waiting_on = vec![0usize; plugins.len()];
+    for &next in after.iter().flatten() {
+        waiting_on[next] += 1;
+    }
+    let masters: Vec<bool> = plugins.iter().map(|(name, header)| is_master_file(name, header)).collect();
+    let mut done = vec![false; plugins.len()];
+    let mut result = Vec::with_capacity(plugins.len());
+    while result.len() < plugins.len() {
+        let ready = |idx: &usize| !done[*idx] && waiting_on[*idx] == 0;
+        let Some(next) = (0..plugins.len())
+            .filter(ready)
+            .find(|&idx| masters[idx])
+            .or_else(|| (0..plugins.len()).find(ready))
+        else {
+            return Err(SortError::Cycle(find_cycle(&after, &done, plugins)));
+        };
+        done[next] = true;
+        for &dependent in &after[next] {
+            waiting_on[dependent] -= 1;
+        }
+        result.push(plugins[next].0.to_owned());
+    }
+    Ok(result)
+}
+
+// every plugin left waits on another one left, so following the edges
+// backwards from any of them has to come back around
+fn find_cycle(after: &[Vec<usize>], done: &[bool], plugins: &[(&str, &PluginHeader)]) -> Vec<String> {
+    let mut before: Vec<Vec<usize>> = vec![Vec::new(); after.len()];
+    for (idx, nexts) in after.iter().enumerate() {
+        for &next in nexts {
+            before[next].push(idx);
+        }
+    }
+    let mut path = vec![done.iter().position(|d| !d).unwrap()];
+    loop {
+        let current = *path.last().unwrap();
+        let prev = before[current].iter().copied().find(|&idx| !done[idx]).unwrap();
+        if let Some(start) = path.iter().position(|&idx| idx == prev) {
+            return path[start..].iter().rev().map(|&idx| plugins[idx].0.to_owned()).collect();
+        }
+        path.push(prev);
+    }
+}
+
+/// Read a plugins.txt, active plugins are marked with a leading `*`
+pub fn read_plugins_txt(input: impl BufRead) -> io::Result<Vec<(String, bool)>> {
+    let mut result = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.strip_prefix('*') {
+            Some(name) => result.push((name.to_owned(), true)),
+            None => result.push((line.to_owned(), false)),
+        }
+    }
+    Ok(result)
+}
+
+pub fn write_plugins_txt(mut out: impl Write, plugins: &[(String, bool)]) -> io::Result<()> {
+    write!(out, "# This file is used by the game to keep track of your downloaded content.\r\n")?;
+    for (name, active) in plugins {
+        match active {
+            true => write!(out, "*{name}\r\n")?,
+            false => write!(out, "{name}\r\n")?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::espparser::tests::tes4;
+    use crate::header::MASTER_FLAG;
+
+    fn header(master: bool, masters: &[&str]) -> PluginHeader {
+        let mut header = PluginHeader::read(&tes4(0.0, masters)[..]).unwrap();
+        if master {
+            header.flags |= MASTER_FLAG;
+        }
+        header
+    }
+
+    #[test]
+    fn sorting() {
+        let skyrim = header(true, &[]);
+        let a = header(false, &["Skyrim.esm", "B.esp"]);
+        let b = header(false, &["Skyrim.esm"]);
+        let flagged = header(true, &["Skyrim.esm"]);
+        let lib = header(false, &["Skyrim.esm"]);
+        let plugins = [("A.esp", &a), ("B.esp", &b), ("Skyrim.esm", &skyrim), ("Flagged.esp", &flagged), ("Lib.esl", &lib)];
+        let order = sort(&plugins, &[]).unwrap();
+        assert_eq!(order, ["Skyrim.esm", "Flagged.esp", "Lib.esl", "B.esp", "A.esp"]);
+
+        let rules = [LoadAfter { plugin: "b.esp".into(), after: "A.esp".into() }];
+        assert_eq!(sort(&plugins[2..], &rules).unwrap(), ["Skyrim.esm", "Flagged.esp", "Lib.esl"]);
+        let c = header(false, &["Skyrim.esm"]);
+        let plugins = [("Skyrim.esm", &skyrim), ("C.esp", &c), ("B.esp", &b)];
+        let rules = [LoadAfter { plugin: "C.esp".into(), after: "B.esp".into() }];
+        assert_eq!(sort(&plugins, &rules).unwrap(), ["Skyrim.esm", "B.esp", "C.esp"]);
+    }
+
+    #[test]
+    fn errors() {
+        let a = header(false, &["Missing.esm"]);
+        assert_eq!(
+            sort(&[("A.esp", &a)], &[]),
+            Err(SortError::MissingMasters(vec![MissingMaster { plugin: "A.esp".into(), master: "Missing.esm".into() }]))
+        );
+
+        let a = header(false, &["C.esp"]);
+        let b = header(false, &["A.esp"]);
+        let c = header(false, &["B.esp"]);
+        let d = header(false, &[]);
+        let Err(SortError::Cycle(cycle)) = sort(&[("D.esp", &d), ("A.esp", &a), ("B.esp", &b), ("C.esp", &c)], &[]) else {
+            panic!("expected a cycle");
+        };
+        assert_eq!(cycle.len(), 3);
+        assert!(!cycle.contains(&"D.esp".to_owned()));
+    }
+
+    #[test]
+    fn plugins_txt() {
+        let plugins = vec![("Skyrim.esm".to_owned(), true), ("Mod.esp".to_owned(), false)];
+        let mut written = Vec::new();
+        write_plugins_txt(&mut written, &plugins).unwrap();
+        assert!(String::from_utf8_lossy(&written).contains("*Skyrim.esm\r\nMod.esp\r\n"));
+        assert_eq!(read_plugins_txt(&written[..]).unwrap(), plugins);
+    }
+}
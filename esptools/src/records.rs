@@ -2,10 +2,21 @@
 //
 // SPDX-License-Identifier: LGPL-3.0-only
 
+use std::borrow::Cow;
+use std::io::{Read, Write};
 use std::mem::{size_of_val, size_of, transmute};
+use std::ptr::addr_of;
 
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 
-use crate::common::ConstantSizedRecord;
+use crate::bsa::MAX_COMPRESSION_RATIO;
+use crate::common::{self, ConstantSizedRecord, Error};
+
+/// Record flag marking data stored as a u32 size followed by a zlib stream
+pub const COMPRESSED_FLAG: u32 = 0x00040000;
+pub const DELETED_FLAG: u32 = 0x20;
+/// for references, the reference starts out disabled
+pub const INITIALLY_DISABLED_FLAG: u32 = 0x800;
 
 
 
@@ -13,6 +24,7 @@ impl ConstantSizedRecord for RecordHeader {
     const SIZE: usize = size_of::<RawRecordHeader>();
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RecordHeader {
     pub typ: [u8; 4],
     pub data_size: u32,
@@ -21,6 +33,13 @@ pub struct RecordHeader {
     pub timestamp: u16,
     pub vcs_info: u16,
     pub internal_version: u16,
+    pub unknown: u16,
+}
+
+impl RecordHeader {
+    pub fn is_compressed(&self) -> bool {
+        self.flags & COMPRESSED_FLAG != 0
+    }
 }
 
 #[repr(C)]
@@ -35,6 +54,7 @@ pub struct RawRecordHeader {
     pub unknown: u16
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GroupHeader {
     pub typ: [u8; 4],
     pub group_size: u32,
@@ -42,6 +62,7 @@ pub struct GroupHeader {
     pub group_type: u32,
     pub timestamp: u16,
     pub vcs_info: u16,
+    pub unknown: u32,
 }
 
 impl ConstantSizedRecord for GroupHeader {
@@ -59,6 +80,49 @@ pub struct RawGroupHeader {
     pub unknown: u32
 }
 
+/// What a group holds, decoded from its `group_type` and `label`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupKind {
+    /// all records of one type, the label is the record type
+    Top([u8; 4]),
+    WorldChildren(u32),
+    InteriorCellBlock(i32),
+    InteriorCellSubBlock(i32),
+    ExteriorCellBlock { x: i16, y: i16 },
+    ExteriorCellSubBlock { x: i16, y: i16 },
+    CellChildren(u32),
+    TopicChildren(u32),
+    CellPersistentChildren(u32),
+    CellTemporaryChildren(u32),
+    CellVisibleDistantChildren(u32),
+    Unknown(u32, [u8; 4]),
+}
+
+impl GroupHeader {
+    pub fn kind(&self) -> GroupKind {
+        use GroupKind::*;
+        let label = self.label;
+        let form_id = u32::from_le_bytes(label);
+        // exterior cell grids store y first
+        let y = i16::from_le_bytes([label[0], label[1]]);
+        let x = i16::from_le_bytes([label[2], label[3]]);
+        match self.group_type {
+            0 => Top(label),
+            1 => WorldChildren(form_id),
+            2 => InteriorCellBlock(form_id as i32),
+            3 => InteriorCellSubBlock(form_id as i32),
+            4 => ExteriorCellBlock { x, y },
+            5 => ExteriorCellSubBlock { x, y },
+            6 => CellChildren(form_id),
+            7 => TopicChildren(form_id),
+            8 => CellPersistentChildren(form_id),
+            9 => CellTemporaryChildren(form_id),
+            10 => CellVisibleDistantChildren(form_id),
+            t => Unknown(t, label),
+        }
+    }
+}
+
 #[repr(C)]
 pub struct RawFieldHeader {
     pub typ: [u8; 4],
@@ -83,7 +147,44 @@ pub struct Field {
     pub data: [u8]
 }
 
+/// Inflate the data of a compressed record
+pub fn decompress(data: &[u8]) -> common::Result<Box<[u8]>> {
+    let (size, compressed) = data.split_first_chunk::<4>().ok_or(Error::BogusSize)?;
+    let size = u32::from_le_bytes(*size) as usize;
+    // the size is read from the plugin, don't allocate more than the data
+    // could inflate to
+    let mut result = Vec::with_capacity(size.min(compressed.len().saturating_mul(MAX_COMPRESSION_RATIO)));
+    ZlibDecoder::new(compressed).take(size as u64 + 1).read_to_end(&mut result)?;
+    if result.len() != size {
+        return Err(Error::BogusSize);
+    }
+    Ok(result.into_boxed_slice())
+}
+
+/// Compress field data into the form compressed records store it in
+pub fn compress(data: &[u8]) -> common::Result<Box<[u8]>> {
+    let mut result = u32::try_from(data.len()).or(Err(Error::BogusSize))?.to_le_bytes().to_vec();
+    let mut encoder = ZlibEncoder::new(&mut result, Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()?;
+    Ok(result.into_boxed_slice())
+}
+
 impl Record {
+    pub fn is_compressed(&self) -> bool {
+        // records in a mapped file aren't necessarily aligned
+        let flags = unsafe { addr_of!(self.header.flags).read_unaligned() };
+        flags & COMPRESSED_FLAG != 0
+    }
+
+    /// The record's field data, inflated if the record is compressed
+    pub fn data(&self) -> common::Result<Cow<'_, [u8]>> {
+        match self.is_compressed() {
+            true => Ok(Cow::Owned(decompress(&self.data)?.into_vec())),
+            false => Ok(Cow::Borrowed(&self.data)),
+        }
+    }
+
     pub fn first_field(&self) -> Option<&Field> {
         fn first_field_header(rec: &Record) -> Option<&RawFieldHeader> {
             if size_of_val(&rec.data) < size_of::<RawFieldHeader>() {
@@ -105,6 +206,93 @@ impl Record {
     }
 }
 
+/// A subrecord, borrowed from its record's data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldRef<'a> {
+    pub typ: [u8; 4],
+    pub data: &'a [u8],
+}
+
+/// Iterator over the fields of a record's (uncompressed) data.
+///
+/// Fields bigger than 64k are preceded by an `XXXX` field holding the real
+/// size, and have a size of zero in their own header. Those are handled here
+/// and never returned.
+pub struct Fields<'a> {
+    data: &'a [u8],
+}
+
+pub fn fields(data: &[u8]) -> Fields<'_> {
+    Fields { data }
+}
+
+impl<'a> Fields<'a> {
+    fn next_header(&mut self) -> common::Result<([u8; 4], usize)> {
+        let Some((header, rest)) = self.data.split_first_chunk::<{ size_of::<RawFieldHeader>() }>() else {
+            return Err(Error::BogusSize);
+        };
+        self.data = rest;
+        let typ = [header[0], header[1], header[2], header[3]];
+        Ok((typ, u16::from_le_bytes([header[4], header[5]]) as usize))
+    }
+
+    fn next_field(&mut self) -> common::Result<FieldRef<'a>> {
+        let (mut typ, mut size) = self.next_header()?;
+        if &typ == b"XXXX" {
+            let real_size = self.data.get(..4).filter(|_| size == 4).ok_or(Error::BogusSize)?;
+            let real_size = u32::from_le_bytes(real_size.try_into().unwrap()) as usize;
+            self.data = &self.data[4..];
+            (typ, _) = self.next_header()?;
+            size = real_size;
+        }
+        let (data, rest) = self.data.split_at_checked(size).ok_or(Error::BogusSize)?;
+        self.data = rest;
+        Ok(FieldRef { typ, data })
+    }
+}
+
+// strings in plugins are nul terminated, but the terminator is sometimes missing
+pub(crate) fn zstring(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+pub(crate) fn le_u32(data: &[u8]) -> common::Result<u32> {
+    Ok(u32::from_le_bytes(data.try_into().map_err(|_| Error::BogusSize)?))
+}
+
+/// Append a field to some record data, preceded by an `XXXX` field if it's
+/// too big for its own header
+pub fn write_field(out: &mut Vec<u8>, typ: [u8; 4], data: &[u8]) {
+    let size = match u16::try_from(data.len()) {
+        Ok(size) => size,
+        Err(_) => {
+            out.extend_from_slice(b"XXXX\x04\x00");
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            0
+        }
+    };
+    out.extend_from_slice(&typ);
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = common::Result<FieldRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let result = self.next_field();
+        if result.is_err() {
+            // don't keep going from the middle of a field
+            self.data = &[];
+        }
+        Some(result)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::Path;
@@ -130,4 +318,26 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn oversized_field() {
+        let big = vec![7u8; 70000];
+        let mut data = Vec::new();
+        data.extend_from_slice(b"EDID\x04\x00abc\0");
+        data.extend_from_slice(b"XXXX\x04\x00");
+        data.extend_from_slice(&(big.len() as u32).to_le_bytes());
+        data.extend_from_slice(b"DATA\x00\x00");
+        data.extend_from_slice(&big);
+        let result: Vec<_> = fields(&data).collect::<common::Result<_>>().unwrap();
+        let mut written = Vec::new();
+        for field in &result {
+            write_field(&mut written, field.typ, field.data);
+        }
+        assert_eq!(written, data);
+        assert_eq!(result, [
+            FieldRef { typ: *b"EDID", data: b"abc\0" },
+            FieldRef { typ: *b"DATA", data: &big },
+        ]);
+        assert!(fields(&data[..data.len() - 1]).last().unwrap().is_err());
+    }
 }
@@ -0,0 +1,393 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Typed views of common record types.
+//!
+//! Form ids inside records are left raw, use a
+//! [MasterResolver](crate::formid::MasterResolver) to make sense of them.
+
+use crate::common::*;
+use crate::espparser::PluginRecord;
+use crate::header::PluginHeader;
+use crate::records::{le_u32, zstring, FieldRef};
+use crate::strings::StringTables;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Game {
+    Tes5,
+    Sse,
+    Fo4,
+}
+
+/// What's needed to decode a record besides the record itself
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeContext<'a> {
+    pub game: Game,
+    /// strings are ids into string tables
+    pub localized: bool,
+    /// the plugin's string tables, used to look up localized strings
+    pub strings: Option<&'a StringTables>,
+}
+
+impl<'a> DecodeContext<'a> {
+    pub fn new(game: Game, header: &PluginHeader) -> Self {
+        Self { game, localized: header.is_localized(), strings: None }
+    }
+
+    pub fn with_strings(self, strings: &'a StringTables) -> Self {
+        Self { strings: Some(strings), ..self }
+    }
+}
+
+/// A string that may live in a string table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LString {
+    String(String),
+    Id(u32),
+}
+
+impl LString {
+    /// Localized strings are looked up in the context's string tables if it
+    /// has them, and left as ids otherwise
+    pub fn decode(data: &[u8], ctx: &DecodeContext) -> Result<Self> {
+        if !ctx.localized {
+            return Ok(LString::String(zstring(data)));
+        }
+        let id = le_u32(data)?;
+        match ctx.strings.and_then(|strings| strings.get(id)) {
+            Some(string) => Ok(LString::String(string.to_owned())),
+            None => Ok(LString::Id(id)),
+        }
+    }
+}
+
+/// A record type with a typed representation. Implementations decide how to
+/// handle differences between games from the [DecodeContext].
+pub trait TypedRecord: Sized {
+    const TYPE: [u8; 4];
+
+    fn decode_fields(fields: &[FieldRef], ctx: &DecodeContext) -> Result<Self>;
+
+    fn decode(record: &PluginRecord, ctx: &DecodeContext) -> Result<Self> {
+        if record.header.typ != Self::TYPE {
+            return Err(Error::InvalidTag);
+        }
+        let fields = record.fields()?.collect::<Result<Vec<_>>>()?;
+        Self::decode_fields(&fields, ctx)
+    }
+}
+
+fn find<'a>(fields: &[FieldRef<'a>], typ: &[u8; 4]) -> Option<&'a [u8]> {
+    fields.iter().find(|f| &f.typ == typ).map(|f| f.data)
+}
+
+fn array<const N: usize>(data: &[u8]) -> Result<[u8; N]> {
+    data.try_into().map_err(|_| Error::BogusSize)
+}
+
+fn form_ids(data: &[u8]) -> Result<Vec<u32>> {
+    if !data.len().is_multiple_of(4) {
+        return Err(Error::BogusSize);
+    }
+    Ok(data.chunks_exact(4).map(|id| u32::from_le_bytes(id.try_into().unwrap())).collect())
+}
+
+/// The editor id of any record
+pub fn editor_id(record: &PluginRecord) -> Result<Option<String>> {
+    for field in record.fields()? {
+        let field = field?;
+        if &field.typ == b"EDID" {
+            return Ok(Some(zstring(field.data)));
+        }
+    }
+    Ok(None)
+}
+
+/// The display name of any record that has one
+pub fn full_name(record: &PluginRecord, ctx: &DecodeContext) -> Result<Option<LString>> {
+    for field in record.fields()? {
+        let field = field?;
+        if &field.typ == b"FULL" {
+            return LString::decode(field.data, ctx).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// The keywords of a record, from its KWDA field
+pub fn keywords(fields: &[FieldRef]) -> Result<Vec<u32>> {
+    find(fields, b"KWDA").map_or(Ok(Vec::new()), form_ids)
+}
+
+/// Offsets of the form ids in a field of a `record_type` record, for the
+/// fields decoded here. Other fields are treated as holding none.
+pub fn form_id_offsets(record_type: &[u8; 4], field: &FieldRef) -> Vec<usize> {
+    match (record_type, &field.typ) {
+        (_, b"KWDA") => (0..field.data.len() / 4).map(|idx| idx * 4).collect(),
+        (b"FLST", b"LNAM") if field.data.len() == 4 => vec![0],
+        (b"LVLI", b"LVLO") if field.data.len() == 12 => vec![4],
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GmstValue {
+    Bool(bool),
+    Int(i32),
+    UInt(u32),
+    Float(f32),
+    String(LString),
+}
+
+/// Game setting, the type of the value is given by the first letter of its editor id
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gmst {
+    pub editor_id: String,
+    pub value: GmstValue,
+}
+
+impl TypedRecord for Gmst {
+    const TYPE: [u8; 4] = *b"GMST";
+
+    fn decode_fields(fields: &[FieldRef], ctx: &DecodeContext) -> Result<Self> {
+        let editor_id = zstring(find(fields, b"EDID").ok_or(Error::ParseError)?);
+        let data = find(fields, b"DATA").ok_or(Error::ParseError)?;
+        let value = match editor_id.as_bytes().first() {
+            Some(b'b') => GmstValue::Bool(le_u32(data)? != 0),
+            Some(b'i') => GmstValue::Int(i32::from_le_bytes(array(data)?)),
+            Some(b'u') => GmstValue::UInt(le_u32(data)?),
+            Some(b'f') => GmstValue::Float(f32::from_le_bytes(array(data)?)),
+            Some(b's') => GmstValue::String(LString::decode(data, ctx)?),
+            _ => return Err(Error::ParseError),
+        };
+        Ok(Self { editor_id, value })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GlobValue {
+    Short(i16),
+    Long(i32),
+    Float(f32),
+}
+
+/// Global variable, always stored as a float whatever its type
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glob {
+    pub editor_id: String,
+    pub constant: bool,
+    pub value: GlobValue,
+}
+
+impl TypedRecord for Glob {
+    const TYPE: [u8; 4] = *b"GLOB";
+
+    fn decode_fields(fields: &[FieldRef], _ctx: &DecodeContext) -> Result<Self> {
+        let editor_id = zstring(find(fields, b"EDID").ok_or(Error::ParseError)?);
+        let value = f32::from_le_bytes(array(find(fields, b"FLTV").ok_or(Error::ParseError)?)?);
+        let value = match find(fields, b"FNAM") {
+            Some(b"s") => GlobValue::Short(value as i16),
+            Some(b"l") => GlobValue::Long(value as i32),
+            Some(b"f") | None => GlobValue::Float(value),
+            Some(_) => return Err(Error::ParseError),
+        };
+        // the constant flag lives in the record header, see [Glob::decode]
+        Ok(Self { editor_id, constant: false, value })
+    }
+
+    fn decode(record: &PluginRecord, ctx: &DecodeContext) -> Result<Self> {
+        if record.header.typ != Self::TYPE {
+            return Err(Error::InvalidTag);
+        }
+        let fields = record.fields()?.collect::<Result<Vec<_>>>()?;
+        let mut result = Self::decode_fields(&fields, ctx)?;
+        result.constant = record.header.flags & 0x40 != 0;
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kywd {
+    pub editor_id: String,
+    /// CNAM, rgba
+    pub color: Option<[u8; 4]>,
+}
+
+impl TypedRecord for Kywd {
+    const TYPE: [u8; 4] = *b"KYWD";
+
+    fn decode_fields(fields: &[FieldRef], _ctx: &DecodeContext) -> Result<Self> {
+        Ok(Self {
+            editor_id: zstring(find(fields, b"EDID").ok_or(Error::ParseError)?),
+            color: find(fields, b"CNAM").map(array).transpose()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flst {
+    pub editor_id: Option<String>,
+    pub forms: Vec<u32>,
+}
+
+impl TypedRecord for Flst {
+    const TYPE: [u8; 4] = *b"FLST";
+
+    fn decode_fields(fields: &[FieldRef], _ctx: &DecodeContext) -> Result<Self> {
+        let forms = fields
+            .iter()
+            .filter(|f| &f.typ == b"LNAM")
+            .map(|f| le_u32(f.data))
+            .collect::<Result<_>>()?;
+        Ok(Self { editor_id: find(fields, b"EDID").map(zstring), forms })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeveledEntry {
+    pub level: u16,
+    pub form_id: u32,
+    pub count: u16,
+    /// fallout 4 only
+    pub chance_none: Option<u8>,
+}
+
+impl LeveledEntry {
+    fn decode(data: &[u8], ctx: &DecodeContext) -> Result<Self> {
+        let data: [u8; 12] = array(data)?;
+        Ok(Self {
+            level: u16::from_le_bytes([data[0], data[1]]),
+            form_id: le_u32(&data[4..8])?,
+            count: u16::from_le_bytes([data[8], data[9]]),
+            chance_none: (ctx.game == Game::Fo4).then_some(data[10]),
+        })
+    }
+}
+
+/// Leveled item list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lvli {
+    pub editor_id: Option<String>,
+    pub chance_none: u8,
+    pub flags: u8,
+    pub entries: Vec<LeveledEntry>,
+}
+
+impl TypedRecord for Lvli {
+    const TYPE: [u8; 4] = *b"LVLI";
+
+    fn decode_fields(fields: &[FieldRef], ctx: &DecodeContext) -> Result<Self> {
+        let byte = |typ| find(fields, typ).and_then(|d: &[u8]| d.first().copied()).unwrap_or(0);
+        let entries = fields
+            .iter()
+            .filter(|f| &f.typ == b"LVLO")
+            .map(|f| LeveledEntry::decode(f.data, ctx))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            editor_id: find(fields, b"EDID").map(zstring),
+            chance_none: byte(b"LVLD"),
+            flags: byte(b"LVLF"),
+            entries,
+        })
+    }
+}
+
+/// Just the names and keywords of an actor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NpcNames {
+    pub editor_id: Option<String>,
+    pub name: Option<LString>,
+    pub short_name: Option<LString>,
+    pub keywords: Vec<u32>,
+}
+
+impl TypedRecord for NpcNames {
+    const TYPE: [u8; 4] = *b"NPC_";
+
+    fn decode_fields(fields: &[FieldRef], ctx: &DecodeContext) -> Result<Self> {
+        let lstring = |typ| find(fields, typ).map(|d| LString::decode(d, ctx)).transpose();
+        Ok(Self {
+            editor_id: find(fields, b"EDID").map(zstring),
+            name: lstring(b"FULL")?,
+            short_name: lstring(b"SHRT")?,
+            keywords: keywords(fields)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::espparser::tests::{field, record};
+
+    const SSE: DecodeContext = DecodeContext { game: Game::Sse, localized: false, strings: None };
+
+    fn parse(typ: &[u8; 4], flags: u32, fields: &[Vec<u8>]) -> PluginRecord {
+        let data = record(typ, flags, 0x800, &fields.concat());
+        let mut reader = crate::espparser::PluginReader::new(&data[..]);
+        match reader.next_event().unwrap() {
+            Some(crate::espparser::PluginEvent::Record(record)) => record,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn gmst() {
+        let record = parse(b"GMST", 0, &[field(b"EDID", b"fJumpHeight\0"), field(b"DATA", &2.5f32.to_le_bytes())]);
+        let gmst = Gmst::decode(&record, &SSE).unwrap();
+        assert_eq!(gmst.value, GmstValue::Float(2.5));
+        let record = parse(b"GMST", 0, &[field(b"EDID", b"sGreeting\0"), field(b"DATA", &7u32.to_le_bytes())]);
+        let localized = DecodeContext { localized: true, ..SSE };
+        assert_eq!(Gmst::decode(&record, &localized).unwrap().value, GmstValue::String(LString::Id(7)));
+        assert!(Glob::decode(&record, &SSE).is_err());
+    }
+
+    #[test]
+    fn glob() {
+        let record =
+            parse(b"GLOB", 0x40, &[field(b"EDID", b"GameHour\0"), field(b"FNAM", b"s"), field(b"FLTV", &3.0f32.to_le_bytes())]);
+        let glob = Glob::decode(&record, &SSE).unwrap();
+        assert_eq!(glob.value, GlobValue::Short(3));
+        assert!(glob.constant);
+    }
+
+    #[test]
+    fn lists() {
+        let record = parse(b"KYWD", 0, &[field(b"EDID", b"VendorItemFood\0")]);
+        assert_eq!(Kywd::decode(&record, &SSE).unwrap(), Kywd { editor_id: "VendorItemFood".into(), color: None });
+
+        let record = parse(b"FLST", 0, &[field(b"LNAM", &0x12u32.to_le_bytes()), field(b"LNAM", &0x13u32.to_le_bytes())]);
+        assert_eq!(Flst::decode(&record, &SSE).unwrap().forms, [0x12, 0x13]);
+
+        let entry = [1, 0, 0, 0, 0x12, 0, 0, 0, 2, 0, 50, 0];
+        let record = parse(b"LVLI", 0, &[field(b"LVLD", &[25]), field(b"LVLF", &[1]), field(b"LVLO", &entry)]);
+        let lvli = Lvli::decode(&record, &SSE).unwrap();
+        assert_eq!((lvli.chance_none, lvli.flags), (25, 1));
+        assert_eq!(lvli.entries, [LeveledEntry { level: 1, form_id: 0x12, count: 2, chance_none: None }]);
+        let fo4 = DecodeContext { game: Game::Fo4, ..SSE };
+        assert_eq!(Lvli::decode(&record, &fo4).unwrap().entries[0].chance_none, Some(50));
+
+        let offsets = |typ, field: &[u8]| form_id_offsets(typ, &crate::records::fields(field).next().unwrap().unwrap());
+        assert_eq!(offsets(b"LVLI", &field(b"LVLO", &entry)), [4]);
+        assert_eq!(offsets(b"FLST", &field(b"LNAM", &[0; 4])), [0]);
+        assert_eq!(offsets(b"ARMO", &field(b"KWDA", &[0; 8])), [0, 4]);
+        assert!(offsets(b"LVLI", &field(b"LNAM", &[0; 4])).is_empty());
+    }
+
+    #[test]
+    fn npc() {
+        let record = parse(b"NPC_", 0, &[
+            field(b"EDID", b"Lydia\0"),
+            field(b"KSIZ", &1u32.to_le_bytes()),
+            field(b"KWDA", &0x13794u32.to_le_bytes()),
+            field(b"FULL", b"Lydia\0"),
+        ]);
+        let npc = NpcNames::decode(&record, &SSE).unwrap();
+        assert_eq!(npc.name, Some(LString::String("Lydia".into())));
+        assert_eq!(npc.short_name, None);
+        assert_eq!(npc.keywords, [0x13794]);
+        assert_eq!(editor_id(&record).unwrap().as_deref(), Some("Lydia"));
+        assert_eq!(full_name(&record, &SSE).unwrap(), npc.name);
+    }
+}
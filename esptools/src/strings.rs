@@ -0,0 +1,230 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek};
+use std::path::Path;
+
+use cap_std::{ambient_authority, fs::Dir};
+
+use crate::bsa::IndexedArchive;
+use crate::common::*;
+use crate::records::{le_u32, zstring};
+
+/// The three kinds of string table, they differ only in how strings are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StringTableKind {
+    /// names, nul terminated
+    Strings,
+    /// descriptions, length prefixed
+    DlStrings,
+    /// dialogue, length prefixed
+    IlStrings,
+}
+
+impl StringTableKind {
+    pub const ALL: [StringTableKind; 3] = [Self::Strings, Self::DlStrings, Self::IlStrings];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Strings => "STRINGS",
+            Self::DlStrings => "DLSTRINGS",
+            Self::IlStrings => "ILSTRINGS",
+        }
+    }
+
+    /// The name of this table for a plugin, i.e. `Skyrim_english.STRINGS`
+    pub fn file_name(self, plugin: &str, language: &str) -> String {
+        let stem = plugin.rsplit_once('.').map_or(plugin, |(stem, _)| stem);
+        format!("{stem}_{language}.{}", self.extension())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StringTable {
+    strings: HashMap<u32, String>,
+}
+
+impl StringTable {
+    /// Parse a table, a u32 count and data size, then a directory of ids and
+    /// offsets into the data that follows it
+    pub fn parse(data: &[u8], kind: StringTableKind) -> Result<Self> {
+        let count = le_u32(data.get(0..4).ok_or(Error::BogusSize)?)? as usize;
+        let data_size = le_u32(data.get(4..8).ok_or(Error::BogusSize)?)? as usize;
+        let directory = data.get(8..8 + count * 8).ok_or(Error::BogusSize)?;
+        let strings_start = 8 + count * 8;
+        let string_data = data.get(strings_start..strings_start + data_size).ok_or(Error::BogusSize)?;
+        let mut strings = HashMap::with_capacity(count);
+        for entry in directory.chunks_exact(8) {
+            let id = le_u32(&entry[0..4])?;
+            let offset = le_u32(&entry[4..8])? as usize;
+            let rest = string_data.get(offset..).ok_or(Error::BogusSize)?;
+            let string = match kind {
+                StringTableKind::Strings => zstring(rest),
+                StringTableKind::DlStrings | StringTableKind::IlStrings => {
+                    let len = le_u32(rest.get(0..4).ok_or(Error::BogusSize)?)? as usize;
+                    zstring(rest.get(4..4 + len).ok_or(Error::BogusSize)?)
+                }
+            };
+            strings.insert(id, string);
+        }
+        Ok(Self { strings })
+    }
+
+    pub fn get(&self, id: u32) -> Option<&str> {
+        self.strings.get(&id).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+/// All the string tables for one plugin
+#[derive(Debug, Clone, Default)]
+pub struct StringTables {
+    pub strings: StringTable,
+    pub dl_strings: StringTable,
+    pub il_strings: StringTable,
+}
+
+impl StringTables {
+    fn table_mut(&mut self, kind: StringTableKind) -> &mut StringTable {
+        match kind {
+            StringTableKind::Strings => &mut self.strings,
+            StringTableKind::DlStrings => &mut self.dl_strings,
+            StringTableKind::IlStrings => &mut self.il_strings,
+        }
+    }
+
+    /// look an id up in every table, ids are unique across a plugin's tables
+    pub fn get(&self, id: u32) -> Option<&str> {
+        self.strings.get(id).or_else(|| self.dl_strings.get(id)).or_else(|| self.il_strings.get(id))
+    }
+
+    /// Load the tables from the `Strings` directory next to `plugin`
+    pub fn load(plugin: &Path, language: &str) -> Result<Self> {
+        let name = plugin.file_name().and_then(|n| n.to_str()).ok_or(Error::ParseError)?;
+        let data_dir = Dir::open_ambient_dir(plugin.parent().unwrap_or(Path::new(".")), ambient_authority())?;
+        Self::load_from_dir(&data_dir, name, language)
+    }
+
+    /// Load the tables for the plugin `name` from the `Strings` directory in
+    /// `data_dir`, it's an error if none of them exist
+    pub fn load_from_dir(data_dir: &Dir, name: &str, language: &str) -> Result<Self> {
+        let mut result = Self::default();
+        let mut found = false;
+        for kind in StringTableKind::ALL {
+            let path = Path::new("Strings").join(kind.file_name(name, language));
+            match data_dir.read(path) {
+                Ok(data) => {
+                    *result.table_mut(kind) = StringTable::parse(&data, kind)?;
+                    found = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match found {
+            true => Ok(result),
+            false => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+
+    /// Load the tables for the plugin `name` from an archive, where they're
+    /// kept under `strings\`
+    pub fn load_from_archive<R: Read + Seek>(archive: &mut IndexedArchive<R>, name: &str, language: &str) -> Result<Self> {
+        let mut result = Self::default();
+        let mut found = false;
+        for kind in StringTableKind::ALL {
+            let path = format!("strings\\{}", kind.file_name(name, language));
+            if let Some(idx) = archive.find(&path) {
+                *result.table_mut(kind) = StringTable::parse(&archive.read(idx)?.data, kind)?;
+                found = true;
+            }
+        }
+        match found {
+            true => Ok(result),
+            false => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::bsa::{ArchiveVersion, BsaWriter};
+    use crate::espparser::tests::{field, record};
+    use crate::espparser::{PluginEvent, PluginReader};
+    use crate::schema::{DecodeContext, Game, LString, NpcNames, TypedRecord};
+
+    fn table(kind: StringTableKind, strings: &[(u32, &str)]) -> Vec<u8> {
+        let mut directory = Vec::new();
+        let mut data = Vec::new();
+        for (id, string) in strings {
+            directory.extend(id.to_le_bytes());
+            directory.extend((data.len() as u32).to_le_bytes());
+            if kind != StringTableKind::Strings {
+                data.extend((string.len() as u32 + 1).to_le_bytes());
+            }
+            data.extend(string.as_bytes());
+            data.push(0);
+        }
+        let mut result = (strings.len() as u32).to_le_bytes().to_vec();
+        result.extend((data.len() as u32).to_le_bytes());
+        result.extend(directory);
+        result.extend(data);
+        result
+    }
+
+    #[test]
+    fn parse() {
+        for kind in StringTableKind::ALL {
+            let parsed = StringTable::parse(&table(kind, &[(1, "Iron Sword"), (7, "")]), kind).unwrap();
+            assert_eq!(parsed.len(), 2);
+            assert_eq!(parsed.get(1), Some("Iron Sword"));
+            assert_eq!(parsed.get(7), Some(""));
+            assert_eq!(parsed.get(2), None);
+        }
+        let mut truncated = table(StringTableKind::DlStrings, &[(1, "Iron Sword")]);
+        truncated.pop();
+        assert!(StringTable::parse(&truncated, StringTableKind::DlStrings).is_err());
+    }
+
+    #[test]
+    fn dir_and_archive() {
+        let dir = cap_tempfile::tempdir(ambient_authority()).unwrap();
+        assert!(StringTables::load_from_dir(&dir, "Mod.esp", "english").is_err());
+        dir.create_dir("Strings").unwrap();
+        dir.write("Strings/Mod_english.STRINGS", table(StringTableKind::Strings, &[(1, "Lydia")])).unwrap();
+        dir.write("Strings/Mod_english.DLSTRINGS", table(StringTableKind::DlStrings, &[(2, "A housecarl")])).unwrap();
+        let tables = StringTables::load_from_dir(&dir, "Mod.esp", "english").unwrap();
+        assert_eq!(tables.get(1), Some("Lydia"));
+        assert_eq!(tables.get(2), Some("A housecarl"));
+        assert!(tables.il_strings.is_empty());
+
+        let strings = table(StringTableKind::Strings, &[(1, "Lydia")]);
+        let mut writer = BsaWriter::new(ArchiveVersion::SkyrimSE);
+        writer.add("Strings/Mod_english.STRINGS", &strings[..]).unwrap();
+        let mut out = Cursor::new(Vec::new());
+        writer.write(&mut out).unwrap();
+        let mut archive = IndexedArchive::new(out).unwrap();
+        let tables = StringTables::load_from_archive(&mut archive, "Mod.esp", "english").unwrap();
+        assert_eq!(tables.get(1), Some("Lydia"));
+
+        // typed records pick up the text
+        let data = record(b"NPC_", 0, 0x800, &field(b"FULL", &1u32.to_le_bytes()));
+        let Some(PluginEvent::Record(npc)) = PluginReader::new(&data[..]).next_event().unwrap() else { unreachable!() };
+        let ctx = DecodeContext { game: Game::Sse, localized: true, strings: None };
+        assert_eq!(NpcNames::decode(&npc, &ctx).unwrap().name, Some(LString::Id(1)));
+        let ctx = ctx.with_strings(&tables);
+        assert_eq!(NpcNames::decode(&npc, &ctx).unwrap().name, Some(LString::String("Lydia".into())));
+    }
+}
@@ -5,8 +5,9 @@
 use camino::Utf8PathBuf;
 use clap::{Args, Parser, Subcommand};
 use enum_dispatch::enum_dispatch;
+use esptools::{cleaning, diff, esl, espparser, header::PluginHeader};
 use mm_api_interaction::{api::sync::download_link, nxm::NXMUrl};
-use mm_store::{OsTreeRepo, mutable_tree::MutableTree, ObjectType, Checksum, RepoRead};
+use mm_store::{OsTreeRepo, fsck::FsckOptions, mutable_tree::MutableTree, prune::PruneOptions, ObjectType, Checksum, RepoRead};
 use serde::{Deserialize, Serialize};
 use std::{
     env::current_exe,
@@ -34,7 +35,8 @@ struct MmCli {
 enum MmCliSubcommands {
     Api(ApiCli),
     Config(ConfigCli),
-    Store(StoreCli)
+    Store(StoreCli),
+    Plugin(PluginCli),
 }
 
 #[derive(Args)]
@@ -125,7 +127,21 @@ enum StoreCliCommands {
         #[arg(id="type")]
         typ: ObjectType,
         checksum: Checksum
-    }
+    },
+    /// Verify every object reachable from the repo's refs
+    Fsck {
+        /// delete corrupt objects so they can be fetched again
+        #[arg(long)]
+        delete_corrupt: bool,
+    },
+    /// Delete objects no ref can reach
+    Prune {
+        #[arg(long)]
+        dry_run: bool,
+        /// only keep this many commits of history on each ref
+        #[arg(long)]
+        depth: Option<usize>,
+    },
 }
 
 impl MmCliCommand for StoreCli {
@@ -161,10 +177,210 @@ impl MmCliCommand for StoreCli {
                     _ => println!("unsupported object type.")
                 }
             }
+            Fsck { delete_corrupt } => {
+                let repo = OsTreeRepo::open(&self.repo_dir)?;
+                let report = repo.fsck(&FsckOptions { delete_corrupt })?;
+                for issue in &report.issues {
+                    println!("{issue}");
+                }
+                println!("checked {} objects, {} problems", report.objects_checked, report.issues.len());
+                if !report.is_ok() {
+                    anyhow::bail!("repo has corrupt or missing objects");
+                }
+            }
+            Prune { dry_run, depth } => {
+                let repo = OsTreeRepo::open(&self.repo_dir)?;
+                let report = repo.prune(&PruneOptions { dry_run, depth, ..Default::default() })?;
+                let verb = if dry_run { "would prune" } else { "pruned" };
+                println!("{verb} {} objects, {} bytes", report.objects_pruned, report.bytes_freed);
+            }
         })
     }
 }
 
+#[derive(Args)]
+struct PluginCli {
+    /// where to look for masters, defaults to the plugin's directory
+    #[arg(long)]
+    data_dir: Option<Utf8PathBuf>,
+    #[command(subcommand)]
+    command: PluginCliCommands,
+}
+
+#[derive(Subcommand)]
+enum PluginCliCommands {
+    /// list identical to master and deleted records
+    Dirty { plugins: Vec<Utf8PathBuf> },
+    /// write a copy of a plugin with ITMs removed and deleted references disabled
+    Clean { plugin: Utf8PathBuf, output: Utf8PathBuf },
+    /// check whether plugins could be flagged as light
+    EslCheck { plugins: Vec<Utf8PathBuf> },
+    /// show the records and fields that changed between two versions of a plugin
+    Diff { old: Utf8PathBuf, new: Utf8PathBuf },
+    /// write a copy of a plugin with the light flag set
+    EslFlag {
+        plugin: Utf8PathBuf,
+        output: Utf8PathBuf,
+        /// flag the plugin even if it isn't eligible
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+fn plugin_name(path: &Utf8PathBuf) -> anyhow::Result<&str> {
+    path.file_name().ok_or_else(|| anyhow::anyhow!("{} isn't a plugin", path))
+}
+
+impl PluginCli {
+    fn load_masters(&self, path: &Utf8PathBuf, plugin: &espparser::Plugin) -> anyhow::Result<Vec<(String, espparser::Plugin)>> {
+        let data_dir = match &self.data_dir {
+            Some(dir) => dir.clone(),
+            None => path.parent().map(Into::into).unwrap_or_default(),
+        };
+        let mut masters = Vec::new();
+        for name in plugin.plugin_header()?.master_names() {
+            let master_path = data_dir.join(name);
+            if !master_path.exists() {
+                log::warn!("master {} of {} not found", name, path);
+                continue;
+            }
+            masters.push((name.to_owned(), espparser::Plugin::open(master_path)?));
+        }
+        Ok(masters)
+    }
+
+    fn dirty(&self, path: &Utf8PathBuf) -> anyhow::Result<()> {
+        let plugin = espparser::Plugin::open(path)?;
+        let masters = self.load_masters(path, &plugin)?;
+        let masters: Vec<_> = masters.iter().map(|(name, master)| (name.as_str(), master)).collect();
+        let masters = cleaning::Masters::new(&plugin, &masters)?;
+        let dirty = cleaning::find_dirty(plugin_name(path)?, &plugin, &masters)?;
+        println!("{}: {} dirty records", path, dirty.len());
+        for record in dirty {
+            println!("  {:?} {} {}", record.kind, String::from_utf8_lossy(&record.typ), record.key);
+        }
+        if !masters.remapped().is_empty() {
+            println!(
+                "  {} number forms differently, ITMs with form ids in fields that aren't decoded may have been missed",
+                masters.remapped().join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    fn esl_report(&self, path: &Utf8PathBuf, plugin: &espparser::Plugin) -> anyhow::Result<esl::EslReport> {
+        let data_dir = match &self.data_dir {
+            Some(dir) => dir.clone(),
+            None => path.parent().map(Into::into).unwrap_or_default(),
+        };
+        // everything else in the data directory could depend on this plugin
+        let mut others = Vec::new();
+        for entry in data_dir.read_dir_utf8()? {
+            let entry = entry?;
+            let is_plugin = ["esp", "esm", "esl"]
+                .iter()
+                .any(|ext| entry.path().extension().is_some_and(|e| e.eq_ignore_ascii_case(ext)));
+            if is_plugin && entry.file_name() != plugin_name(path)? {
+                others.push((entry.file_name().to_owned(), PluginHeader::open(entry.path())?));
+            }
+        }
+        let others: Vec<_> = others.iter().map(|(name, header)| (name.as_str(), header)).collect();
+        Ok(esl::check(plugin_name(path)?, plugin, &others)?)
+    }
+}
+
+impl MmCliCommand for PluginCli {
+    fn run(self) -> anyhow::Result<()> {
+        use PluginCliCommands::*;
+        match &self.command {
+            Dirty { plugins } => {
+                for path in plugins {
+                    self.dirty(path)?;
+                }
+            }
+            Clean { plugin: path, output } => {
+                let mut plugin = espparser::Plugin::open(path)?;
+                let masters = self.load_masters(path, &plugin)?;
+                let masters: Vec<_> = masters.iter().map(|(name, master)| (name.as_str(), master)).collect();
+                let masters = cleaning::Masters::new(&plugin, &masters)?;
+                let cleaned = cleaning::clean(plugin_name(path)?, &mut plugin, &masters)?;
+                plugin.write(io::BufWriter::new(File::create(output)?))?;
+                println!("cleaned {} records from {}", cleaned.len(), path);
+            }
+            EslCheck { plugins } => {
+                for path in plugins {
+                    let plugin = espparser::Plugin::open(path)?;
+                    let report = self.esl_report(path, &plugin)?;
+                    let verdict = if report.eligible() { "can be flagged light" } else { "can't be flagged light" };
+                    println!("{}: {} ({} new records)", path, verdict, report.new_records);
+                    for id in &report.out_of_range {
+                        println!("  {:06X} is outside {:03X}-{:03X}", id, report.range.start(), report.range.end());
+                    }
+                    if !report.eligible() && !report.dependents.is_empty() {
+                        println!("  renumbering it would break {}", report.dependents.join(", "));
+                    }
+                }
+            }
+            Diff { old, new } => {
+                let old_plugin = espparser::Plugin::open(old)?;
+                let new_plugin = espparser::Plugin::open(new)?;
+                for record in diff::diff(plugin_name(new)?, &old_plugin, &new_plugin)? {
+                    let editor_id = record.editor_id.as_deref().unwrap_or("");
+                    let typ = String::from_utf8_lossy(&record.typ);
+                    let (fields, flags) = match record.change {
+                        diff::RecordChange::Added => {
+                            println!("+ {} {} {}", typ, record.key, editor_id);
+                            continue;
+                        }
+                        diff::RecordChange::Removed => {
+                            println!("- {} {} {}", typ, record.key, editor_id);
+                            continue;
+                        }
+                        diff::RecordChange::Modified { fields, flags } => (fields, flags),
+                    };
+                    println!("~ {} {} {}", typ, record.key, editor_id);
+                    if let Some((old_flags, new_flags)) = flags {
+                        println!("    flags {:08X} -> {:08X}", old_flags, new_flags);
+                    }
+                    for field in fields {
+                        match field {
+                            diff::FieldChange::Added { typ, data } => {
+                                println!("    + {} {:02X?}", String::from_utf8_lossy(&typ), data)
+                            }
+                            diff::FieldChange::Removed { typ, data } => {
+                                println!("    - {} {:02X?}", String::from_utf8_lossy(&typ), data)
+                            }
+                            diff::FieldChange::Changed { typ, old, new } => {
+                                println!("    ~ {} {:02X?} -> {:02X?}", String::from_utf8_lossy(&typ), old, new)
+                            }
+                        }
+                    }
+                }
+            }
+            EslFlag { plugin: path, output, force } => {
+                let mut plugin = espparser::Plugin::open(path)?;
+                let report = self.esl_report(path, &plugin)?;
+                match report.ineligible() {
+                    Some(_) if *force => (),
+                    Some(esl::Ineligible::TooManyRecords) => anyhow::bail!(
+                        "{} can't be flagged light, it has {} new records and only {} fit",
+                        path,
+                        report.new_records,
+                        report.range.clone().count()
+                    ),
+                    Some(esl::Ineligible::OutOfRange) => {
+                        anyhow::bail!("{} can't be flagged light, {} ids are out of range", path, report.out_of_range.len())
+                    }
+                    None => (),
+                }
+                esl::set_light(&mut plugin, true);
+                plugin.write(io::BufWriter::new(File::create(output)?))?;
+            }
+        }
+        Ok(())
+    }
+}
+
 macro_rules! stamp_out_settings {
     ($($vis:vis $name:ident : $typ:ty)*) => {
         #[derive(Serialize, Deserialize, Debug, Default)]
@@ -1,7 +1,10 @@
+use std::io::{Read, Seek};
+
+use esptools::bsa::IndexedArchive;
 use mm_archive::traits::CompressionMethod;
 use serde::{Serialize, Deserialize};
 use zvariant::Type;
-use crate::Checksum;
+use crate::{mutable_tree::MutableTree, Checksum, ObjectType, OsTreeRepo, RepoError, RepoErrorKind, RepoWrite};
 
 
 #[derive(Debug, Serialize, Deserialize, Type)]
@@ -12,3 +15,48 @@ pub struct ThinArchiveEntry {
     compression_method: CompressionMethod,
     compression_level: i8
 }
+
+/// Split a path from an archive into the names to use in a tree, archives
+/// mostly use `\` but some tools write `/`. Empty and `.` components are
+/// dropped and `..` is rejected, a tree can't point outside itself.
+fn archive_path_components(path: &str) -> Result<Vec<&str>, RepoError> {
+    let mut result = Vec::new();
+    for component in path.split(['\\', '/']) {
+        match component {
+            "" | "." => (),
+            ".." => return Err(RepoErrorKind::InvalidFilename(path.into()).into()),
+            _ => result.push(component),
+        }
+    }
+    Ok(result)
+}
+
+impl OsTreeRepo {
+    /// Write every named file in a bsa into the repo, adding them to `mtree`
+    /// at their paths inside the archive. Files are stored loose, so
+    /// archives that share content share objects.
+    pub fn write_bsa_to_mtree<R: Read + Seek>(
+        &mut self,
+        archive: &mut IndexedArchive<R>,
+        mtree: &mut MutableTree,
+    ) -> Result<(), RepoError> {
+        for idx in 0..archive.files().len() {
+            let Some(path) = &archive.files()[idx].path else {
+                continue;
+            };
+            let path = String::from_utf8_lossy(path.to_bytes()).into_owned();
+            let mut components = archive_path_components(&path)?;
+            let Some(file_name) = components.pop() else {
+                return Err(RepoErrorKind::InvalidFilename(path.into()).into());
+            };
+            let data = archive.read(idx)?;
+            let chk = self.write_with_type(&*data.data, ObjectType::File)?;
+            let mut dir = &mut *mtree;
+            for component in components {
+                dir = dir.ensure_dir(component)?;
+            }
+            dir.replace_file(file_name, chk)?;
+        }
+        Ok(())
+    }
+}
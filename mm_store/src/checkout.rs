@@ -0,0 +1,101 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::io;
+
+use cap_std::fs::Dir;
+
+use crate::{loose_path, Checksum, DirTree, ObjectType, OsTreeRepo, RepoError, RepoErrorKind, RepoMode, RepoRead};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CheckoutMode {
+    /// hardlink files to their objects, copying them if the destination is on
+    /// another filesystem. Checked out files must not be modified in place.
+    #[default]
+    Hardlink,
+    /// copy files with copy_file_range where available, which reflinks on
+    /// filesystems that support it
+    Copy,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CheckoutOptions {
+    pub mode: CheckoutMode,
+    /// replace files that already exist in the destination instead of failing
+    pub overwrite: bool,
+}
+
+impl OsTreeRepo {
+    /// Recreate the tree of a commit, or a dirtree, in `dest`
+    pub fn checkout(
+        &self,
+        commit_or_tree: &Checksum,
+        dest: &Dir,
+        options: &CheckoutOptions,
+    ) -> Result<(), RepoError> {
+        if self.config.core.mode != RepoMode::BareUserOnly {
+            return Err(RepoErrorKind::UnsupportedMode(self.config.core.mode).into());
+        }
+        let tree = if self.try_contains(ObjectType::Commit, commit_or_tree)? {
+            let commit = self.load_commit(commit_or_tree)?;
+            self.load_dirtree(&commit.root_dirtree_checksum)?
+        } else if self.try_contains(ObjectType::DirTree, commit_or_tree)? {
+            self.load_dirtree(commit_or_tree)?
+        } else {
+            return Err(RepoErrorKind::RevNotFound(commit_or_tree.to_string()).into());
+        };
+        self.checkout_tree(&tree, dest, options)
+    }
+
+    /// With `overwrite`, whatever is in the way of a file or directory is
+    /// replaced, including a directory where the tree has a file and the
+    /// other way around.
+    pub fn checkout_tree(&self, tree: &DirTree, dest: &Dir, options: &CheckoutOptions) -> Result<(), RepoError> {
+        if self.config.core.mode != RepoMode::BareUserOnly {
+            return Err(RepoErrorKind::UnsupportedMode(self.config.core.mode).into());
+        }
+        for (name, chk) in &tree.files {
+            self.checkout_file(chk, dest, name, options)?;
+        }
+        for (name, chks) in &tree.dirs {
+            match dest.create_dir(name) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && options.overwrite => {
+                    if !dest.symlink_metadata(name)?.is_dir() {
+                        dest.remove_file(name)?;
+                        dest.create_dir(name)?;
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let subtree = self.load_dirtree(&chks.checksum)?;
+            self.checkout_tree(&subtree, &dest.open_dir(name)?, options)?;
+        }
+        Ok(())
+    }
+
+    fn checkout_file(&self, chk: &Checksum, dest: &Dir, name: &str, options: &CheckoutOptions) -> Result<(), RepoError> {
+        let object = loose_path(chk, ObjectType::File, self.config.core.mode);
+        if options.overwrite {
+            match dest.symlink_metadata(name) {
+                Ok(meta) if meta.is_dir() => dest.remove_dir_all(name)?,
+                Ok(_) => dest.remove_file(name)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if options.mode == CheckoutMode::Hardlink {
+            match self.objects_dir.hard_link(&object, dest, name) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::CrossesDevices => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if dest.try_exists(name)? {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+        }
+        self.objects_dir.copy(&object, dest, name)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::collections::BTreeMap;
+
+use crate::{mutable_tree::MutableTree, Checksum, DirTree, OsTreeRepo, RepoError};
+
+/// A path more than one commit provides, paths use `/` and the casing of
+/// the winning commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileConflict {
+    pub path: String,
+    /// the commit whose version of the path is used
+    pub winner: Checksum,
+    /// earlier commits that also had it, in order
+    pub losers: Vec<Checksum>,
+}
+
+#[derive(Debug)]
+struct UnionFile {
+    name: String,
+    chk: Checksum,
+    /// every layer that had this file, the last one wins
+    layers: Vec<usize>,
+}
+
+#[derive(Debug, Default)]
+struct UnionDir {
+    name: String,
+    /// both keyed by lowercased name
+    files: BTreeMap<String, UnionFile>,
+    dirs: BTreeMap<String, UnionDir>,
+}
+
+impl UnionDir {
+    fn layers(&self, out: &mut Vec<usize>) {
+        for file in self.files.values() {
+            out.extend(&file.layers);
+        }
+        for dir in self.dirs.values() {
+            dir.layers(out);
+        }
+    }
+
+    fn into_mtree(self) -> Result<MutableTree<'static>, RepoError> {
+        let mut mtree = MutableTree::new();
+        for file in self.files.into_values() {
+            mtree.replace_file(&file.name, file.chk)?;
+        }
+        for dir in self.dirs.into_values() {
+            let name = dir.name.clone();
+            *mtree.ensure_dir(&name)? = dir.into_mtree()?;
+        }
+        Ok(mtree)
+    }
+}
+
+fn join(path: &str, name: &str) -> String {
+    match path.is_empty() {
+        true => name.to_owned(),
+        false => format!("{path}/{name}"),
+    }
+}
+
+impl OsTreeRepo {
+    fn merge_dirtree(
+        &self,
+        tree: DirTree,
+        layer: usize,
+        into: &mut UnionDir,
+        path: &str,
+        conflicts: &mut Vec<(String, usize, Vec<usize>)>,
+    ) -> Result<(), RepoError> {
+        for (name, chk) in tree.files {
+            let key = name.to_lowercase();
+            let mut layers = Vec::new();
+            if let Some(dir) = into.dirs.remove(&key) {
+                // a file replacing a whole directory overrides every layer
+                // that had something in it
+                dir.layers(&mut layers);
+                layers.sort();
+                layers.dedup();
+            }
+            let file = into.files.entry(key).or_insert(UnionFile { name: String::new(), chk: Default::default(), layers });
+            file.name = name;
+            file.chk = chk;
+            file.layers.push(layer);
+        }
+        for (name, chks) in tree.dirs {
+            let key = name.to_lowercase();
+            if let Some(file) = into.files.remove(&key) {
+                conflicts.push((join(path, &name), layer, file.layers));
+            }
+            let dir = into.dirs.entry(key).or_insert_with(|| UnionDir { name: name.clone(), ..Default::default() });
+            let dir_path = join(path, &dir.name);
+            let subtree = self.load_dirtree(&chks.checksum)?;
+            self.merge_dirtree(subtree, layer, dir, &dir_path, conflicts)?;
+        }
+        Ok(())
+    }
+
+    fn file_conflicts(dir: &UnionDir, path: &str, conflicts: &mut Vec<(String, usize, Vec<usize>)>) {
+        for file in dir.files.values() {
+            let mut layers = file.layers.clone();
+            layers.dedup();
+            if let Some((&winner, losers)) = layers.split_last() {
+                if !losers.is_empty() {
+                    conflicts.push((join(path, &file.name), winner, losers.to_vec()));
+                }
+            }
+        }
+        for subdir in dir.dirs.values() {
+            Self::file_conflicts(subdir, &join(path, &subdir.name), conflicts);
+        }
+    }
+
+    /// Merge the root trees of `commits` into one tree, later commits
+    /// override files from earlier ones. Paths are compared case
+    /// insensitively like they are on windows, directories keep the name
+    /// they were first seen with and files the name from the winning commit.
+    ///
+    /// The result can be [committed](Self::commit) and checked out to deploy
+    /// a load order of mods.
+    pub fn union_commits(&self, commits: &[Checksum]) -> Result<(MutableTree<'static>, Vec<FileConflict>), RepoError> {
+        let mut root = UnionDir::default();
+        let mut conflicts = Vec::new();
+        for (layer, chk) in commits.iter().enumerate() {
+            let commit = self.load_commit(chk)?;
+            let tree = self.load_dirtree(&commit.root_dirtree_checksum)?;
+            self.merge_dirtree(tree, layer, &mut root, "", &mut conflicts)?;
+        }
+        Self::file_conflicts(&root, "", &mut conflicts);
+        conflicts.sort_by(|a, b| a.0.cmp(&b.0));
+        let conflicts = conflicts
+            .into_iter()
+            .map(|(path, winner, losers)| FileConflict {
+                path,
+                winner: commits[winner].clone(),
+                losers: losers.into_iter().map(|l| commits[l].clone()).collect(),
+            })
+            .collect();
+        Ok((root.into_mtree()?, conflicts))
+    }
+}
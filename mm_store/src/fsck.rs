@@ -0,0 +1,187 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+    io::{copy, Read},
+};
+
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use zvariant::Type;
+
+use crate::{
+    from_slice_gv, loose_path, write_header, Checksum, Commit, DirMeta, DirTree, ObjectType, OsTreeRepo, RepoError,
+    RepoErrorKind, RepoMode, RepoRead,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// nothing with this checksum is in the repo
+    Missing,
+    /// the object's content doesn't hash to its checksum
+    Corrupt,
+    /// the checksum is in the repo, but only as a different type of object
+    WrongType,
+    /// the object hashes correctly but couldn't be parsed
+    Malformed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsckIssue {
+    pub typ: ObjectType,
+    pub checksum: Checksum,
+    pub problem: FsckProblem,
+    /// set if the object was removed so it can be fetched again
+    pub deleted: bool,
+}
+
+impl Display for FsckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {} {}", self.problem, self.typ, self.checksum)?;
+        if self.deleted {
+            f.write_str(" (deleted)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct FsckOptions {
+    /// delete corrupt and malformed objects
+    pub delete_corrupt: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct FsckReport {
+    /// number of distinct objects that were read and hashed
+    pub objects_checked: usize,
+    pub issues: Vec<FsckIssue>,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+struct Fsck<'a> {
+    repo: &'a OsTreeRepo,
+    options: &'a FsckOptions,
+    seen: HashSet<(ObjectType, Checksum)>,
+    report: FsckReport,
+}
+
+impl Fsck<'_> {
+    fn issue(&mut self, typ: ObjectType, chk: &Checksum, problem: FsckProblem) -> Result<(), RepoError> {
+        let deleted = self.options.delete_corrupt && matches!(problem, FsckProblem::Corrupt | FsckProblem::Malformed);
+        if deleted {
+            self.repo
+                .objects_dir
+                .remove_file(loose_path(chk, typ, self.repo.config.core.mode))?;
+        }
+        self.report.issues.push(FsckIssue { typ, checksum: chk.clone(), problem, deleted });
+        Ok(())
+    }
+
+    /// Open an object that hasn't been checked yet, recording it as missing
+    /// if it isn't there
+    fn open(&mut self, typ: ObjectType, chk: &Checksum) -> Result<Option<cap_std::fs::File>, RepoError> {
+        if !self.seen.insert((typ, chk.clone())) {
+            return Ok(None);
+        }
+        if let Some(object) = self.repo.try_get(typ, chk)? {
+            self.report.objects_checked += 1;
+            return Ok(Some(object));
+        }
+        let other_types = [ObjectType::File, ObjectType::DirTree, ObjectType::DirMeta, ObjectType::Commit];
+        let mut problem = FsckProblem::Missing;
+        for other in other_types.into_iter().filter(|&other| other != typ) {
+            if self.repo.try_contains(other, chk)? {
+                problem = FsckProblem::WrongType;
+            }
+        }
+        self.issue(typ, chk, problem)?;
+        Ok(None)
+    }
+
+    /// read, hash and parse a metadata object
+    fn load<T: DeserializeOwned + Type>(&mut self, typ: ObjectType, chk: &Checksum) -> Result<Option<T>, RepoError> {
+        let Some(mut object) = self.open(typ, chk)? else {
+            return Ok(None);
+        };
+        let mut bytes = Vec::new();
+        object.read_to_end(&mut bytes)?;
+        if Sha256::digest(&bytes).as_slice() != chk.as_ref() {
+            self.issue(typ, chk, FsckProblem::Corrupt)?;
+            return Ok(None);
+        }
+        match from_slice_gv::<T>(&bytes) {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => {
+                self.issue(typ, chk, FsckProblem::Malformed)?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn file(&mut self, chk: &Checksum) -> Result<(), RepoError> {
+        let Some(mut object) = self.open(ObjectType::File, chk)? else {
+            return Ok(());
+        };
+        // hashed the same way as RepoWrite::write_with_type
+        let mut hasher = Sha256::new();
+        write_header(&mut hasher, &mut object)?;
+        copy(&mut object, &mut hasher)?;
+        if hasher.finalize().as_slice() != chk.as_ref() {
+            self.issue(ObjectType::File, chk, FsckProblem::Corrupt)?;
+        }
+        Ok(())
+    }
+
+    fn dirtree(&mut self, chk: &Checksum, meta_chk: &Checksum) -> Result<(), RepoError> {
+        self.load::<DirMeta>(ObjectType::DirMeta, meta_chk)?;
+        let Some(tree) = self.load::<DirTree>(ObjectType::DirTree, chk)? else {
+            return Ok(());
+        };
+        for chk in tree.files.values() {
+            self.file(chk)?;
+        }
+        for chks in tree.dirs.values() {
+            self.dirtree(&chks.checksum, &chks.meta_checksum)?;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self, chk: &Checksum) -> Result<(), RepoError> {
+        let mut next = Some(chk.clone());
+        while let Some(chk) = next.take() {
+            let Some(commit) = self.load::<Commit>(ObjectType::Commit, &chk)? else {
+                break;
+            };
+            self.dirtree(&commit.root_dirtree_checksum, &commit.root_dirmeta_checksum)?;
+            // history may have been pruned, so a missing parent is fine
+            if !commit.parent.as_ref().is_empty() && self.repo.try_contains(ObjectType::Commit, &commit.parent)? {
+                next = Some(commit.parent);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl OsTreeRepo {
+    /// Check every object reachable from the repo's refs, re-hashing each
+    /// one and parsing the metadata objects
+    pub fn fsck(&self, options: &FsckOptions) -> Result<FsckReport, RepoError> {
+        if self.config.core.mode != RepoMode::BareUserOnly {
+            return Err(RepoErrorKind::UnsupportedMode(self.config.core.mode).into());
+        }
+        let mut fsck = Fsck { repo: self, options, seen: HashSet::new(), report: FsckReport::default() };
+        for chk in self.list_refs()?.values() {
+            fsck.commit(chk)?;
+        }
+        Ok(fsck.report)
+    }
+}
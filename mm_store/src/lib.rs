@@ -22,4 +22,8 @@ pub mod repo;
 pub mod mutable_tree;
 pub mod perms;
 pub mod archive;
+pub mod checkout;
+pub mod deploy;
+pub mod fsck;
+pub mod prune;
 pub use crate::repo::*;
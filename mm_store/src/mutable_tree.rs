@@ -23,6 +23,10 @@ pub struct MutableTreeWhole<'repo> {
 }
 
 impl<'repo> MutableTreeLazy<'repo> {
+    pub fn checksums(&self) -> &DirTreeChecksums {
+        &self.checksums
+    }
+
     pub fn to_whole(&self) -> Result<MutableTreeWhole<'repo>, RepoError> {
         let dirtree: DirTree = self.repo.try_load(&self.checksums.checksum)?.unwrap();
         Ok(MutableTreeWhole {
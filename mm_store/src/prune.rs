@@ -0,0 +1,147 @@
+// SPDX-FileCopyrightText: Charles Barto
+//
+// SPDX-License-Identifier: LGPL-3.0-only
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use crate::{Checksum, Commit, DirTree, ObjectType, OsTreeRepo, RepoError, RepoErrorKind, RepoMode, RepoReadExt};
+
+#[derive(Debug, Clone)]
+pub struct PruneOptions {
+    /// only report what would be deleted
+    pub dry_run: bool,
+    /// keep this many of the latest commits on each ref, and all of them if
+    /// unset
+    pub depth: Option<usize>,
+    /// Objects modified more recently than this are kept even if they're
+    /// unreachable, since a writer may have stored them and not yet
+    /// committed or set a ref pointing at them.
+    pub min_age: Duration,
+}
+
+impl Default for PruneOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            depth: None,
+            min_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PruneReport {
+    pub objects_pruned: usize,
+    /// size of the pruned objects, space used by hardlinked checkouts of
+    /// them isn't freed until those are removed too
+    pub bytes_freed: u64,
+}
+
+impl OsTreeRepo {
+    fn mark_dirtree(
+        &self,
+        chk: &Checksum,
+        meta_chk: &Checksum,
+        reachable: &mut HashSet<(ObjectType, Checksum)>,
+    ) -> Result<(), RepoError> {
+        reachable.insert((ObjectType::DirMeta, meta_chk.clone()));
+        if !reachable.insert((ObjectType::DirTree, chk.clone())) {
+            return Ok(());
+        }
+        let Some(tree) = RepoReadExt::<DirTree>::try_load(self, chk)? else {
+            return Ok(());
+        };
+        for chk in tree.files.into_values() {
+            reachable.insert((ObjectType::File, chk));
+        }
+        for chks in tree.dirs.into_values() {
+            self.mark_dirtree(&chks.checksum, &chks.meta_checksum, reachable)?;
+        }
+        Ok(())
+    }
+
+    /// every object reachable from a ref, following at most `depth` commits
+    fn reachable(&self, depth: Option<usize>) -> Result<HashSet<(ObjectType, Checksum)>, RepoError> {
+        let mut reachable = HashSet::new();
+        // how many commits the walks through each commit had left, a ref that
+        // reaches a commit with more left has to keep going past it
+        let mut walked: HashMap<Checksum, usize> = HashMap::new();
+        for chk in self.list_refs()?.into_values() {
+            let mut next = Some(chk);
+            let mut left = depth.unwrap_or(usize::MAX);
+            while let Some(chk) = next.take() {
+                if left == 0 || walked.get(&chk).is_some_and(|&seen| seen >= left) {
+                    break;
+                }
+                let first_visit = walked.insert(chk.clone(), left).is_none();
+                left -= 1;
+                let Some(commit) = RepoReadExt::<Commit>::try_load(self, &chk)? else {
+                    break;
+                };
+                if first_visit {
+                    reachable.insert((ObjectType::Commit, chk));
+                    self.mark_dirtree(&commit.root_dirtree_checksum, &commit.root_dirmeta_checksum, &mut reachable)?;
+                }
+                if !commit.parent.as_ref().is_empty() {
+                    next = Some(commit.parent);
+                }
+            }
+        }
+        Ok(reachable)
+    }
+
+    /// Delete loose objects that can't be reached from any ref.
+    ///
+    /// Writers only move finished objects into `objects/`, and the
+    /// directories there are never removed, so a concurrent
+    /// `OsTreeTempFile::commit` can't fail because of a prune. Objects newer
+    /// than [PruneOptions::min_age] are kept so ones written for a commit
+    /// that isn't finished yet survive.
+    pub fn prune(&self, options: &PruneOptions) -> Result<PruneReport, RepoError> {
+        if self.config.core.mode != RepoMode::BareUserOnly {
+            return Err(RepoErrorKind::UnsupportedMode(self.config.core.mode).into());
+        }
+        let cutoff = SystemTime::now().checked_sub(options.min_age).unwrap_or(SystemTime::UNIX_EPOCH);
+        let reachable = self.reachable(options.depth)?;
+        let mut report = PruneReport::default();
+        for prefix in self.objects_dir.entries()? {
+            let prefix = prefix?;
+            let Some(prefix_name) = prefix.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            let dir = prefix.open_dir()?;
+            for object in dir.entries()? {
+                let object = object?;
+                let name = object.file_name();
+                let Some((rest, ext)) = name.to_str().and_then(|n| n.split_once('.')) else {
+                    continue;
+                };
+                let (Ok(chk), Ok(typ)) = (Checksum::from_str(&format!("{prefix_name}{rest}")), ObjectType::from_str(ext))
+                else {
+                    continue;
+                };
+                if reachable.contains(&(typ, chk)) {
+                    continue;
+                }
+                let metadata = object.metadata()?;
+                if metadata.modified()?.into_std() > cutoff {
+                    continue;
+                }
+                if !options.dry_run {
+                    dir.remove_file(&name)?;
+                }
+                report.objects_pruned += 1;
+                report.bytes_freed += metadata.len();
+            }
+        }
+        Ok(report)
+    }
+}
+
@@ -21,13 +21,14 @@ use std::{
     mem::MaybeUninit,
     path::{Path, PathBuf},
     str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
 use strum_macros::{AsRefStr, Display, EnumString};
 use thiserror::Error;
 use zvariant::{gvariant, serialized::{Context, Data, Format}, to_bytes, Endian, OwnedValue, Type};
 
 #[repr(transparent)]
-#[derive(Serialize, Deserialize, Type, Default, Clone)]
+#[derive(Serialize, Deserialize, Type, Default, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Checksum(pub(self) Box<[u8]>);
 
 impl<T> From<T> for Checksum
@@ -62,7 +63,7 @@ fn to_bytes_gv(value: &(impl Serialize + Type)) -> Vec<u8> {
     to_bytes(ctx, value).unwrap().to_vec()
 }
 
-fn from_slice_gv<'de, 'r: 'de, T: DeserializeOwned + Type>(slice: &'r [u8]) -> zvariant::Result<T> {
+pub(crate) fn from_slice_gv<'de, 'r: 'de, T: DeserializeOwned + Type>(slice: &'r [u8]) -> zvariant::Result<T> {
     let ctx = Context::new_gvariant(Endian::Big, 0);
     
     let data: Data<'de, 'static> = zvariant::serialized::Data::new(slice, ctx);
@@ -95,7 +96,7 @@ macro_rules! stamp_out_object_enum {
         !,
         $($name2:ident $(=$n2:literal)?),*
     ) => {
-        #[derive(Debug, Display, PartialEq, Clone, Copy, AsRefStr, EnumString)]
+        #[derive(Debug, Display, PartialEq, Eq, Hash, Clone, Copy, AsRefStr, EnumString)]
         #[strum(serialize_all = "lowercase")]
         pub enum ObjectType {
             $($name1 $(= $n)?,)*
@@ -143,6 +144,21 @@ pub enum RepoMode {
     BareSplitXattrs,
 }
 
+/// How file objects are stored. Repos from before file objects held only
+/// their content don't say, and can't be read by this version.
+#[derive(
+    Debug, Display, SerializeDisplay, EnumString, DeserializeFromStr, Copy, Clone, PartialEq, Default,
+)]
+#[strum(serialize_all = "kebab-case")]
+pub enum FileLayout {
+    /// the file header followed by the content
+    #[default]
+    WithHeader,
+    /// only the content, the header is hashed but not stored so objects can
+    /// be hardlinked out of the repo
+    ContentOnly,
+}
+
 #[test]
 fn test_repo_mode() {
     assert_eq!(RepoMode::Bare.to_string(), "bare");
@@ -219,11 +235,42 @@ pub struct DirTreeChecksums {
 
 #[derive(Serialize, Deserialize, Debug, Type, Default, Clone)]
 #[zvariant(signature = "(a(say)a(sayay))")]
+#[serde(into = "DirTreeRepr", from = "DirTreeRepr")]
 pub struct DirTree {
     pub files: BTreeMap<String, Checksum>,
     pub dirs: BTreeMap<String, DirTreeChecksums>,
 }
 
+// gvariant has no ordered maps, so a dirtree is stored as arrays of
+// (name, checksum) sorted by name, which serde can't get from a BTreeMap
+#[derive(Serialize, Deserialize)]
+struct DirTreeRepr(Vec<(String, Checksum)>, Vec<(String, Checksum, Checksum)>);
+
+impl From<DirTree> for DirTreeRepr {
+    fn from(tree: DirTree) -> Self {
+        Self(
+            tree.files.into_iter().collect(),
+            tree.dirs
+                .into_iter()
+                .map(|(name, chk)| (name, chk.checksum, chk.meta_checksum))
+                .collect(),
+        )
+    }
+}
+
+impl From<DirTreeRepr> for DirTree {
+    fn from(repr: DirTreeRepr) -> Self {
+        Self {
+            files: repr.0.into_iter().collect(),
+            dirs: repr
+                .1
+                .into_iter()
+                .map(|(name, checksum, meta_checksum)| (name, DirTreeChecksums { checksum, meta_checksum }))
+                .collect(),
+        }
+    }
+}
+
 /// This is the file header in archive-z2 mode, and also the "synthetic" file
 /// header for other modes. in non-archive modes the gvariant serialization of
 /// this is hashed but it's not actually written out because it's stored in the filesystem
@@ -287,10 +334,10 @@ impl DirTreeChecksums {
 
 #[derive(Debug)]
 pub struct OsTreeRepo {
-    repo_dir: Dir,
-    objects_dir: Dir,
-    tmp_dir_fd: Dir,
-    config: RepoConfig,
+    pub(crate) repo_dir: Dir,
+    pub(crate) objects_dir: Dir,
+    pub(crate) tmp_dir_fd: Dir,
+    pub(crate) config: RepoConfig,
 }
 
 #[derive(Error, Debug)]
@@ -303,12 +350,24 @@ pub enum RepoErrorKind {
     InvalidMtree(String),
     #[error("Repo is malformed.")]
     MalformedRepo,
+    #[error("Invalid ref name: {0:?}")]
+    InvalidRef(String),
+    #[error("Rev {0:?} not found")]
+    RevNotFound(String),
+    #[error("Rev {0:?} matches more than one commit")]
+    AmbiguousRev(String),
+    #[error("Unsupported repo mode: {0}")]
+    UnsupportedMode(RepoMode),
+    #[error("Unsupported file object layout: {0}")]
+    UnsupportedFileLayout(FileLayout),
     #[error("variant error")]
     Variant(#[from] zvariant::Error),
     #[error("IO Error")]
     Io(#[from] io::Error),
     #[error("Format error")]
     Format(#[from] fmt::Error),
+    #[error("Archive error")]
+    Archive(#[from] esptools::Error),
 }
 #[derive(Error, Debug)]
 #[error("{source}")]
@@ -333,6 +392,8 @@ where
 pub struct RepoCoreConfig {
     pub repo_version: u32,
     pub mode: RepoMode,
+    #[serde(default)]
+    pub file_layout: FileLayout,
 }
 
 impl Default for RepoCoreConfig {
@@ -340,13 +401,14 @@ impl Default for RepoCoreConfig {
         Self {
             repo_version: 1,
             mode: RepoMode::BareUserOnly,
+            file_layout: FileLayout::ContentOnly,
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct RepoConfig {
-    core: RepoCoreConfig,
+    pub(crate) core: RepoCoreConfig,
 }
 
 pub mod traits {
@@ -414,7 +476,7 @@ pub mod traits {
     }
 }
 
-fn write_header(mut w: impl Write, object: impl Read) -> io::Result<()> {
+pub(crate) fn write_header(mut w: impl Write, object: impl Read) -> io::Result<()> {
     let ctx = Context::new_gvariant(Endian::Big, 0);
     let header = FileHeader::default();
     let header_data = to_bytes(ctx, &header).unwrap();
@@ -433,13 +495,12 @@ impl<R: Read> traits::RepoWrite<R> for OsTreeRepo {
     fn write_with_type(&mut self, mut object: R, typ: ObjectType) -> Result<Checksum, Self::Error> {
         let mut temp_file = self.tmpfile_for_type(typ)?;
         let mut hasher: Sha256 = Sha256::new();
+        // the header is only hashed, the file itself is the content so it can
+        // be hardlinked out of the repo
         if typ == ObjectType::File && self.config.core.mode != RepoMode::ArchiveZ2 {
             write_header(&mut hasher, &mut object)?;
         }
         let mut tee = (&mut hasher).tee(&mut temp_file);
-        if typ == ObjectType::File && self.config.core.mode != RepoMode::ArchiveZ2 {
-            write_header(&mut tee, &mut object)?;
-        }
         // write to the hasher and the file
         copy(&mut object, &mut tee)?;
         let chk: Checksum = hasher.finalize().to_vec().into_boxed_slice().into();
@@ -492,7 +553,6 @@ impl traits::RepoRead for OsTreeRepo {
             unimplemented!()
         }
         let p = loose_path(chk, typ, self.config.core.mode);
-        println!("{:?}", p);
         match self.objects_dir.open(p) {
             Ok(f) => Ok(Some(f)),
             Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
@@ -526,7 +586,7 @@ impl<'repo> OsTreeTempFile<'repo> {
         let final_name = loose_path(&chk, self.typ, self.repo.config.core.mode);
         // TODO: implement rename in cap-tempfile, and use that instaed of this two-stage deal
         self.file.replace(&temp_name)?;
-        self.repo.objects_dir.create_dir_all(&final_name)?;
+        self.repo.objects_dir.create_dir_all(final_name.parent().unwrap())?;
         self.repo
             .tmp_dir_fd
             .rename(temp_name, &self.repo.objects_dir, final_name)?;
@@ -534,6 +594,11 @@ impl<'repo> OsTreeTempFile<'repo> {
     }
 }
 
+/// The ref each installed version of a mod is committed to
+pub fn mod_ref(game: &str, mod_id: impl Display) -> String {
+    format!("mods/{game}/{mod_id}")
+}
+
 impl OsTreeRepo {
     const STATE_DIRS: &[&'static str] = &[
         "tmp",
@@ -581,6 +646,9 @@ impl OsTreeRepo {
         let objects_dir = repo_dir.open_dir("objects")?;
         let config: RepoConfig = serde_ini::from_str(&repo_dir.read_to_string("config")?)
             .or(Err(RepoError::from(RepoErrorKind::MalformedRepo)))?;
+        if config.core.file_layout != FileLayout::ContentOnly {
+            return Err(RepoErrorKind::UnsupportedFileLayout(config.core.file_layout).into());
+        }
         Ok(OsTreeRepo {
             objects_dir: repo_dir.open_dir("objects")?,
             tmp_dir_fd: repo_dir.open_dir("tmp")?,
@@ -633,6 +701,184 @@ impl OsTreeRepo {
         Ok(from_slice_gv::<DirTree>(&bytes)?)
     }
 
+    pub fn load_commit(&self, chk: &Checksum) -> Result<Commit, RepoError> {
+        if self.config.core.mode != RepoMode::BareUserOnly {
+            return Err(RepoErrorKind::UnsupportedMode(self.config.core.mode).into());
+        }
+
+        let mut bytes = Vec::new();
+        self.object_fd(ObjectType::Commit, chk)?
+            .read_to_end(&mut bytes)?;
+        Ok(from_slice_gv::<Commit>(&bytes)?)
+    }
+
+    /// Write out `mtree_root` and a commit pointing at it, returning the
+    /// commit's checksum. The commit isn't reachable from any ref until
+    /// [Self::set_ref] is called with it.
+    pub fn commit(
+        &mut self,
+        mtree_root: MutableTree,
+        parent: Option<&Checksum>,
+        subject: &str,
+        body: &str,
+        metadata: BTreeMap<String, OwnedValue>,
+    ) -> Result<Checksum, RepoError> {
+        use traits::RepoWriteObject;
+        let root = mtree_root.into_lazy(self)?.checksums().clone();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let commit = Commit {
+            metadata,
+            parent: parent.cloned().unwrap_or_default(),
+            related_objects: Vec::new(),
+            subject: subject.to_owned(),
+            body: body.to_owned(),
+            timestamp,
+            root_dirtree_checksum: root.checksum,
+            root_dirmeta_checksum: root.meta_checksum,
+        };
+        self.write(&commit)
+    }
+
+    fn check_ref_name(name: &str) -> Result<(), RepoError> {
+        let valid = !name.is_empty()
+            && name.split('/').all(|c| !c.is_empty() && c != "." && c != "..")
+            && !name.contains(|c: char| c == '^' || c == ':' || c == '\\' || c.is_whitespace());
+        match valid {
+            true => Ok(()),
+            false => Err(RepoErrorKind::InvalidRef(name.to_owned()).into()),
+        }
+    }
+
+    /// Point the local ref `name` (e.g. `mods/skyrimse/1234`) at `chk`,
+    /// creating it if needed
+    pub fn set_ref(&mut self, name: &str, chk: &Checksum) -> Result<(), RepoError> {
+        Self::check_ref_name(name)?;
+        let path = Path::new("refs/heads").join(name);
+        let parent = path.parent().unwrap();
+        self.repo_dir.create_dir_all(parent)?;
+        let parent = self.repo_dir.open_dir(parent)?;
+        // write then rename so readers never see a half written ref
+        let mut temp = TempFile::new(&parent)?;
+        writeln!(temp, "{chk}")?;
+        temp.replace(path.file_name().unwrap())?;
+        Ok(())
+    }
+
+    /// Remove the local ref `name`, returns false if it didn't exist
+    pub fn delete_ref(&mut self, name: &str) -> Result<bool, RepoError> {
+        Self::check_ref_name(name)?;
+        match self.repo_dir.remove_file(Path::new("refs/heads").join(name)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read_ref(&self, name: &str) -> Result<Option<Checksum>, RepoError> {
+        let path = match name.split_once(':') {
+            Some((remote, name)) => Path::new("refs/remotes").join(remote).join(name),
+            None => Path::new("refs/heads").join(name),
+        };
+        if name.split(':').any(|part| Self::check_ref_name(part).is_err()) {
+            return Ok(None);
+        }
+        match self.repo_dir.read_to_string(path) {
+            Ok(content) => Ok(Some(
+                content.trim().parse().or(Err(RepoError::from(RepoErrorKind::MalformedRepo)))?,
+            )),
+            Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::IsADirectory) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list_refs_in(dir: &Dir, prefix: &str, refs: &mut BTreeMap<String, Checksum>) -> Result<(), RepoError> {
+        for item in dir.entries()? {
+            let item = item?;
+            let name = item
+                .file_name()
+                .into_string()
+                .map_err(RepoErrorKind::InvalidFilename)?;
+            let name = format!("{prefix}{name}");
+            if item.file_type()?.is_dir() {
+                Self::list_refs_in(&item.open_dir()?, &format!("{name}/"), refs)?;
+            } else {
+                let chk = dir
+                    .read_to_string(item.file_name())?
+                    .trim()
+                    .parse()
+                    .or(Err(RepoError::from(RepoErrorKind::MalformedRepo)))?;
+                refs.insert(name, chk);
+            }
+        }
+        Ok(())
+    }
+
+    /// Every ref in the repo and what it points at, remote refs are named
+    /// `remote:ref`
+    pub fn list_refs(&self) -> Result<BTreeMap<String, Checksum>, RepoError> {
+        let mut refs = BTreeMap::new();
+        Self::list_refs_in(&self.repo_dir.open_dir("refs/heads")?, "", &mut refs)?;
+        for remote in self.repo_dir.open_dir("refs/remotes")?.entries()? {
+            let remote = remote?;
+            let name = remote
+                .file_name()
+                .into_string()
+                .map_err(RepoErrorKind::InvalidFilename)?;
+            Self::list_refs_in(&remote.open_dir()?, &format!("{name}:"), &mut refs)?;
+        }
+        Ok(refs)
+    }
+
+    /// find the commit whose checksum starts with `prefix`
+    fn resolve_partial_checksum(&self, prefix: &str) -> Result<Option<Checksum>, RepoError> {
+        if prefix.len() < 2 || !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+        let prefix = prefix.to_ascii_lowercase();
+        let dir = match self.objects_dir.open_dir(&prefix[..2]) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut found = None;
+        for item in dir.entries()? {
+            let name = item?.file_name();
+            let Some(rest) = name.to_str().and_then(|n| n.strip_suffix(".commit")) else {
+                continue;
+            };
+            if !rest.starts_with(&prefix[2..]) {
+                continue;
+            }
+            if found.is_some() {
+                return Err(RepoErrorKind::AmbiguousRev(prefix).into());
+            }
+            found = Some(format!("{}{rest}", &prefix[..2]));
+        }
+        found
+            .map(|chk| chk.parse().or(Err(RepoError::from(RepoErrorKind::MalformedRepo))))
+            .transpose()
+    }
+
+    /// Resolve a ref name, full commit checksum or unique prefix of one to a
+    /// commit checksum. Each trailing `^` steps to the parent commit.
+    pub fn resolve_rev(&self, rev: &str) -> Result<Checksum, RepoError> {
+        let base = rev.trim_end_matches('^');
+        let not_found = || RepoError::from(RepoErrorKind::RevNotFound(rev.to_owned()));
+        let mut chk = match self.read_ref(base)? {
+            Some(chk) => chk,
+            None => self.resolve_partial_checksum(base)?.ok_or_else(not_found)?,
+        };
+        for _ in base.len()..rev.len() {
+            chk = self.load_commit(&chk)?.parent;
+            if chk.as_ref().is_empty() {
+                return Err(not_found());
+            }
+        }
+        Ok(chk)
+    }
+
     pub fn write_dirmeta(&mut self, meta: &DirMeta) -> io::Result<Checksum> {
         let (chk, val) = gv_hash_and_val(meta);
         let mut fd = self.new_object_fd_mut(ObjectType::DirMeta, &chk)?;
@@ -2,11 +2,14 @@
 //
 // SPDX-License-Identifier: LGPL-3.0-only
 
-use std::{fs::File, io::Read};
+use std::{collections::BTreeMap, fs::File, io::Read, time::Duration};
 
 use camino::{Utf8Path, Utf8PathBuf};
 
-use mm_store::{*, mutable_tree::MutableTree};
+use esptools::bsa::{ArchiveVersion, BsaWriter, IndexedArchive};
+use cap_std::{ambient_authority, fs::Dir};
+use mm_store::{*, checkout::{CheckoutMode, CheckoutOptions}, deploy::FileConflict, fsck::{FsckOptions, FsckProblem}, mutable_tree::MutableTree, prune::{PruneOptions, PruneReport}};
+use zvariant::OwnedValue;
 
 fn datapath() -> Utf8PathBuf {
     let mut datapath = Utf8PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -14,14 +17,24 @@ fn datapath() -> Utf8PathBuf {
     datapath
 }
 
+fn repo_path(name: &str) -> Utf8PathBuf {
+    Utf8PathBuf::from_iter([env!("CARGO_TARGET_TMPDIR"), name].iter())
+}
+
 fn testrepo(name: &str) -> Result<OsTreeRepo, RepoError> {
-    let repo_path = Utf8PathBuf::from_iter(
-        [env!("CARGO_TARGET_TMPDIR"), name].iter(),
-    );
+    let repo_path = repo_path(name);
     _ = std::fs::remove_dir_all(&repo_path);
     OsTreeRepo::create(&repo_path)
 }
 
+/// reopen a test repo with its config claiming it's a bare repo
+fn reopen_as_bare(name: &str) -> OsTreeRepo {
+    let config = repo_path(name).join("config");
+    let text = std::fs::read_to_string(&config).unwrap();
+    std::fs::write(&config, text.replace("bare-user-only", "bare")).unwrap();
+    OsTreeRepo::open(&repo_path(name)).unwrap()
+}
+
 #[test]
 fn test1() -> Result<(), RepoError> {
     let tmpdir = env!("CARGO_TARGET_TMPDIR");
@@ -30,6 +43,21 @@ fn test1() -> Result<(), RepoError> {
     Ok(())
 }
 
+#[test]
+fn test_file_layout() {
+    testrepo("test_file_layout").unwrap();
+    let config = repo_path("test_file_layout").join("config");
+    let text = std::fs::read_to_string(&config).unwrap();
+    assert!(text.contains("file_layout=content-only"));
+    OsTreeRepo::open(&repo_path("test_file_layout")).unwrap();
+
+    // repos from before file objects were content only have no layout
+    let old: String = text.lines().filter(|l| !l.starts_with("file_layout")).map(|l| format!("{l}\n")).collect();
+    std::fs::write(&config, old).unwrap();
+    let err = OsTreeRepo::open(&repo_path("test_file_layout")).unwrap_err();
+    assert_eq!(err.to_string(), "Unsupported file object layout: with-header");
+}
+
 #[test]
 fn test_write_tree_1() {
     let mut repo = testrepo("test_write_tree_1").unwrap();
@@ -38,6 +66,303 @@ fn test_write_tree_1() {
     mtree.make_lazy(&mut repo).unwrap();
 
 }
+
+#[test]
+fn test_dirtree_encoding() {
+    // a(say)a(sayay), the same bytes ostree writes for a dirtree
+    let mut tree = DirTree::default();
+    tree.files.insert("a".into(), Checksum::from(vec![1, 2]));
+    tree.dirs.insert("d".into(), DirTreeChecksums { checksum: vec![3].into(), meta_checksum: vec![4].into() });
+    let ctx = zvariant::serialized::Context::new_gvariant(zvariant::Endian::Big, 0);
+    let bytes = zvariant::to_bytes(ctx, &tree).unwrap();
+    assert_eq!(&*bytes, [
+        b'a', 0, 1, 2, 2, 5, // ("a", [1, 2]) and the array's offset
+        b'd', 0, 3, 4, 3, 2, 6, // ("d", [3], [4]) and the array's offset
+        6, // offset of the end of the files array
+    ]);
+    let (decoded, _): (DirTree, _) = bytes.deserialize().unwrap();
+    assert_eq!(decoded.files["a"].as_ref(), [1, 2]);
+    assert_eq!(decoded.dirs["d"].meta_checksum.as_ref(), [4]);
+}
+
+#[test]
+fn test_write_bsa_to_mtree() {
+    let mut repo = testrepo("test_write_bsa_to_mtree").unwrap();
+    let mut mtree = MutableTree::new();
+    let bsa = Utf8PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../esptools/tests/testdata/test1.bsa");
+    let mut archive = IndexedArchive::open(bsa).unwrap();
+    repo.write_bsa_to_mtree(&mut archive, &mut mtree).unwrap();
+
+    // all three files in the archive have the same content, so share an object
+    let objects = Utf8PathBuf::from_iter([env!("CARGO_TARGET_TMPDIR"), "test_write_bsa_to_mtree", "objects"]);
+    let file_objects = std::fs::read_dir(objects)
+        .unwrap()
+        .flat_map(|dir| std::fs::read_dir(dir.unwrap().path()).unwrap())
+        .filter(|f| f.as_ref().unwrap().path().extension().is_some_and(|e| e == "file"))
+        .count();
+    assert_eq!(file_objects, 1);
+}
+#[test]
+fn test_commit_and_refs() {
+    let mut repo = testrepo("test_commit_and_refs").unwrap();
+    let mut mtree = MutableTree::new();
+    repo.write_dirpath_to_mtree(&datapath().join("tree1"), &mut mtree).unwrap();
+    let mut metadata = BTreeMap::new();
+    metadata.insert("version".to_owned(), OwnedValue::from(1u32));
+    let first = repo.commit(mtree, None, "Install 1.0", "", metadata).unwrap();
+
+    let commit = repo.load_commit(&first).unwrap();
+    assert_eq!(commit.subject, "Install 1.0");
+    assert!(commit.parent.as_ref().is_empty());
+    assert_eq!(u32::try_from(&commit.metadata["version"]).unwrap(), 1);
+    let tree = repo.load_dirtree(&commit.root_dirtree_checksum).unwrap();
+    assert_eq!(tree.files.keys().collect::<Vec<_>>(), ["test1"]);
+
+    let name = mod_ref("skyrimse", 1234);
+    repo.set_ref(&name, &first).unwrap();
+    let mut mtree = MutableTree::new();
+    repo.write_dirpath_to_mtree(&datapath(), &mut mtree).unwrap();
+    let second = repo.commit(mtree, Some(&first), "Install 1.1", "", BTreeMap::new()).unwrap();
+    repo.set_ref(&name, &second).unwrap();
+
+    assert_eq!(repo.list_refs().unwrap().into_iter().collect::<Vec<_>>(), [(name.clone(), second.clone())]);
+    assert_eq!(repo.resolve_rev(&name).unwrap(), second);
+    assert_eq!(repo.resolve_rev(&format!("{name}^")).unwrap(), first);
+    assert_eq!(repo.resolve_rev(&second.to_string()).unwrap(), second);
+    assert_eq!(repo.resolve_rev(&first.to_string()[..10]).unwrap(), first);
+    assert!(repo.resolve_rev(&format!("{name}^^")).is_err());
+    assert!(repo.resolve_rev("mods/skyrimse/1").is_err());
+    assert!(repo.set_ref("../config", &first).is_err());
+
+    let bare = reopen_as_bare("test_commit_and_refs");
+    assert_eq!(bare.load_commit(&first).unwrap_err().to_string(), "Unsupported repo mode: bare");
+    assert_eq!(bare.resolve_rev(&format!("{name}^")).unwrap_err().to_string(), "Unsupported repo mode: bare");
+
+    assert!(repo.delete_ref(&name).unwrap());
+    assert!(repo.list_refs().unwrap().is_empty());
+}
+
+#[test]
+fn test_checkout() {
+    let mut repo = testrepo("test_checkout").unwrap();
+    let mut mtree = MutableTree::new();
+    repo.write_dirpath_to_mtree(&datapath(), &mut mtree).unwrap();
+    let commit = repo.commit(mtree, None, "", "", BTreeMap::new()).unwrap();
+
+    let checkouts = Utf8PathBuf::from_iter([env!("CARGO_TARGET_TMPDIR"), "test_checkout_dest"]);
+    _ = std::fs::remove_dir_all(&checkouts);
+    for (name, mode) in [("hardlink", CheckoutMode::Hardlink), ("copy", CheckoutMode::Copy)] {
+        let path = checkouts.join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        let dest = Dir::open_ambient_dir(&path, ambient_authority()).unwrap();
+        let options = CheckoutOptions { mode, ..Default::default() };
+        repo.checkout(&commit, &dest, &options).unwrap();
+        assert_eq!(
+            std::fs::read(path.join("tree1/test1")).unwrap(),
+            std::fs::read(datapath().join("tree1/test1")).unwrap()
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let links = std::fs::metadata(path.join("tree1/test1")).unwrap().nlink();
+            assert_eq!(links > 1, mode == CheckoutMode::Hardlink);
+        }
+
+        // checking out over an existing tree needs overwrite
+        assert!(repo.checkout(&commit, &dest, &options).is_err());
+        let options = CheckoutOptions { overwrite: true, ..options };
+        repo.checkout(&commit, &dest, &options).unwrap();
+    }
+
+    // a dirtree can be checked out directly
+    let root = repo.load_commit(&commit).unwrap().root_dirtree_checksum;
+    let dest = cap_tempfile::tempdir(ambient_authority()).unwrap();
+    repo.checkout(&root, &dest, &CheckoutOptions::default()).unwrap();
+    assert!(dest.is_file("tree1/test1"));
+
+    // overwrite replaces a directory where the tree has a file and the other way around
+    let options = CheckoutOptions { overwrite: true, ..Default::default() };
+    let dest = cap_tempfile::tempdir(ambient_authority()).unwrap();
+    dest.create_dir_all("tree1/test1/nested").unwrap();
+    dest.write("tree1/test1/nested/file", "in the way").unwrap();
+    repo.checkout(&root, &dest, &options).unwrap();
+    assert_eq!(dest.read("tree1/test1").unwrap(), std::fs::read(datapath().join("tree1/test1")).unwrap());
+    let dest = cap_tempfile::tempdir(ambient_authority()).unwrap();
+    dest.write("tree1", "in the way").unwrap();
+    repo.checkout(&root, &dest, &options).unwrap();
+    assert!(dest.is_file("tree1/test1"));
+
+    // other modes aren't supported yet, and say so
+    let repo = reopen_as_bare("test_checkout");
+    let err = repo.checkout(&commit, &dest, &options).unwrap_err();
+    assert_eq!(err.to_string(), "Unsupported repo mode: bare");
+}
+
+fn commit_files(repo: &mut OsTreeRepo, files: &[(&str, &str)]) -> Checksum {
+    let mut mtree = MutableTree::new();
+    for (path, content) in files {
+        let chk = repo.write_with_type(content.as_bytes(), ObjectType::File).unwrap();
+        let (dirs, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let mut dir = &mut mtree;
+        for component in dirs.split('/').filter(|c| !c.is_empty()) {
+            dir = dir.ensure_dir(component).unwrap();
+        }
+        dir.replace_file(name, chk).unwrap();
+    }
+    repo.commit(mtree, None, "", "", BTreeMap::new()).unwrap()
+}
+
+#[test]
+fn test_union_commits() {
+    let mut repo = testrepo("test_union_commits").unwrap();
+    let a = commit_files(&mut repo, &[("Textures/sky.dds", "a"), ("meshes/tree.nif", "a"), ("a.esp", "a")]);
+    let b = commit_files(&mut repo, &[("textures/SKY.dds", "b"), ("b.esp", "b")]);
+    let c = commit_files(&mut repo, &[("Meshes", "c"), ("TEXTURES/Sky.dds", "c")]);
+
+    let (mtree, conflicts) = repo.union_commits(&[a.clone(), b.clone(), c.clone()]).unwrap();
+    assert_eq!(conflicts, [
+        FileConflict { path: "Meshes".into(), winner: c.clone(), losers: vec![a.clone()] },
+        FileConflict { path: "Textures/Sky.dds".into(), winner: c.clone(), losers: vec![a.clone(), b.clone()] },
+    ]);
+
+    let deployment = repo.commit(mtree, None, "deployment", "", BTreeMap::new()).unwrap();
+    let dest = cap_tempfile::tempdir(ambient_authority()).unwrap();
+    repo.checkout(&deployment, &dest, &CheckoutOptions::default()).unwrap();
+    assert_eq!(dest.read_to_string("Textures/Sky.dds").unwrap(), "c");
+    assert_eq!(dest.read_to_string("Meshes").unwrap(), "c");
+    assert_eq!(dest.read_to_string("a.esp").unwrap(), "a");
+    assert_eq!(dest.read_to_string("b.esp").unwrap(), "b");
+    assert_eq!(dest.entries().unwrap().count(), 4);
+}
+
+#[test]
+fn test_fsck() {
+    let mut repo = testrepo("test_fsck").unwrap();
+    let commit = commit_files(&mut repo, &[("a.esp", "a"), ("meshes/b.nif", "b"), ("c.esp", "c")]);
+    repo.set_ref("mods/skyrimse/1", &commit).unwrap();
+    let report = repo.fsck(&FsckOptions::default()).unwrap();
+    assert!(report.is_ok());
+    // the commit, two dirtrees, one shared dirmeta and three files
+    assert_eq!(report.objects_checked, 7);
+
+    let objects = Utf8PathBuf::from_iter([env!("CARGO_TARGET_TMPDIR"), "test_fsck", "objects"]);
+    let mut object_path = |content: &str| {
+        let chk = repo.write_with_type(content.as_bytes(), ObjectType::File).unwrap();
+        let path = loose_path(&chk, ObjectType::File, RepoMode::BareUserOnly);
+        (chk, objects.join(path.to_str().unwrap()))
+    };
+    let (corrupt, corrupt_path) = object_path("a");
+    std::fs::write(&corrupt_path, "not a").unwrap();
+    let (moved, moved_path) = object_path("b");
+    std::fs::rename(&moved_path, moved_path.with_extension("dirmeta")).unwrap();
+
+    let report = repo.fsck(&FsckOptions { delete_corrupt: true }).unwrap();
+    let issues: Vec<_> = report.issues.iter().map(|i| (i.checksum.clone(), i.problem, i.deleted)).collect();
+    assert_eq!(issues.len(), 2);
+    assert!(issues.contains(&(corrupt.clone(), FsckProblem::Corrupt, true)));
+    assert!(issues.contains(&(moved, FsckProblem::WrongType, false)));
+    assert!(!corrupt_path.exists());
+
+    let report = repo.fsck(&FsckOptions::default()).unwrap();
+    assert!(report.issues.iter().any(|i| i.checksum == corrupt && i.problem == FsckProblem::Missing));
+
+    let err = reopen_as_bare("test_fsck").fsck(&FsckOptions::default()).unwrap_err();
+    assert_eq!(err.to_string(), "Unsupported repo mode: bare");
+}
+
+#[test]
+fn test_prune() {
+    let mut repo = testrepo("test_prune").unwrap();
+    let first = commit_files(&mut repo, &[("a.esp", "a"), ("b.esp", "b")]);
+    let mut mtree = MutableTree::new();
+    for (name, content) in [("a.esp", "a"), ("c.esp", "c")] {
+        let chk = repo.write_with_type(content.as_bytes(), ObjectType::File).unwrap();
+        mtree.replace_file(name, chk).unwrap();
+    }
+    let second = repo.commit(mtree, Some(&first), "", "", BTreeMap::new()).unwrap();
+    repo.set_ref("mods/skyrimse/1", &second).unwrap();
+    // uninstalled, nothing points at it
+    commit_files(&mut repo, &[("d.esp", "dd")]);
+
+    // everything was just written
+    assert_eq!(repo.prune(&PruneOptions::default()).unwrap(), PruneReport::default());
+
+    let options = PruneOptions { dry_run: true, min_age: Duration::ZERO, ..Default::default() };
+    let dry_run = repo.prune(&options).unwrap();
+    // the commit, its root dirtree and d.esp
+    assert_eq!(dry_run.objects_pruned, 3);
+    assert!(dry_run.bytes_freed >= 2);
+    let options = PruneOptions { dry_run: false, ..options };
+    assert_eq!(repo.prune(&options).unwrap(), dry_run);
+    assert_eq!(repo.prune(&options).unwrap(), PruneReport::default());
+
+    // dropping history frees the first commit, its tree and b.esp
+    let options = PruneOptions { depth: Some(1), ..options };
+    assert_eq!(repo.prune(&options).unwrap().objects_pruned, 3);
+    assert!(repo.fsck(&FsckOptions::default()).unwrap().is_ok());
+    assert_eq!(repo.resolve_rev("mods/skyrimse/1").unwrap(), second);
+    assert!(!repo.contains(ObjectType::Commit, &first));
+
+    let err = reopen_as_bare("test_prune").prune(&options).unwrap_err();
+    assert_eq!(err.to_string(), "Unsupported repo mode: bare");
+}
+
+#[test]
+fn test_prune_shared_history() {
+    let mut repo = testrepo("test_prune_shared_history").unwrap();
+    let mut commit = |parent: Option<&Checksum>, content: &str| {
+        let mut mtree = MutableTree::new();
+        let chk = repo.write_with_type(content.as_bytes(), ObjectType::File).unwrap();
+        mtree.replace_file("a.esp", chk).unwrap();
+        repo.commit(mtree, parent, "", "", BTreeMap::new()).unwrap()
+    };
+    let q = commit(None, "q");
+    let p = commit(Some(&q), "p");
+    let s = commit(Some(&p), "s");
+    // refs are walked in order, a keeps s and p, then b reaches p again with
+    // room left for q
+    repo.set_ref("mods/skyrimse/a", &s).unwrap();
+    repo.set_ref("mods/skyrimse/b", &p).unwrap();
+
+    let options = PruneOptions { depth: Some(2), min_age: Duration::ZERO, ..Default::default() };
+    assert_eq!(repo.prune(&options).unwrap(), PruneReport::default());
+    for chk in [&s, &p, &q] {
+        assert!(repo.contains(ObjectType::Commit, chk));
+    }
+
+    // with only a left, q is too far back
+    repo.delete_ref("mods/skyrimse/b").unwrap();
+    assert_eq!(repo.prune(&options).unwrap().objects_pruned, 3);
+    assert!(!repo.contains(ObjectType::Commit, &q));
+    assert!(repo.fsck(&FsckOptions::default()).unwrap().is_ok());
+}
+
+#[test]
+fn test_write_bsa_paths() {
+    let archive = |paths: &[&str]| {
+        let mut writer = BsaWriter::new(ArchiveVersion::SkyrimSE);
+        for path in paths {
+            writer.add(path, &b"data"[..]).unwrap();
+        }
+        let mut out = std::io::Cursor::new(Vec::new());
+        writer.write(&mut out).unwrap();
+        IndexedArchive::new(out).unwrap()
+    };
+    let mut repo = testrepo("test_write_bsa_paths").unwrap();
+    let mut mtree = MutableTree::new();
+    let mut bsa = archive(&["textures\\\\.\\sky.dds"]);
+    repo.write_bsa_to_mtree(&mut bsa, &mut mtree).unwrap();
+    let commit = repo.commit(mtree, None, "", "", BTreeMap::new()).unwrap();
+    let root = repo.load_dirtree(&repo.load_commit(&commit).unwrap().root_dirtree_checksum).unwrap();
+    assert!(root.files.is_empty());
+    let textures = repo.load_dirtree(&root.dirs["textures"].checksum).unwrap();
+    assert_eq!(textures.files.keys().collect::<Vec<_>>(), ["sky.dds"]);
+    assert!(textures.dirs.is_empty());
+
+    let mut bsa = archive(&["meshes\\..\\..\\evil.nif"]);
+    assert!(repo.write_bsa_to_mtree(&mut bsa, &mut MutableTree::new()).is_err());
+}
+
 // #[test]
 // fn test_matches_hash_file() -> io::Result<()> {
 